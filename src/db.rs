@@ -11,6 +11,62 @@ pub struct Database {
     pub http_response: Vec<(Label, Vec<http::Signature>)>,
 }
 
+#[derive(Clone, Copy, Debug, PartialEq)]
+pub enum Direction {
+    /// from client to server, e.g. SYN or HTTP request.
+    Request,
+    /// from server to client, e.g. SYN+ACK or HTTP response.
+    Response,
+}
+
+#[derive(Clone, Copy, Debug, PartialEq)]
+pub enum MatchQuality {
+    /// matched a specified signature.
+    Exact,
+    /// matched a generic signature.
+    Generic,
+    /// matched after ignoring some quirks or the TTL distance.
+    Fuzzy,
+}
+
+#[derive(Clone, Debug, PartialEq)]
+pub struct TcpMatch<'a> {
+    pub label: &'a Label,
+    pub sig: &'a tcp::Signature,
+    pub quality: MatchQuality,
+    /// distance to the host, based on the initial TTL of the signature.
+    pub distance: u8,
+}
+
+#[derive(Clone, Debug, PartialEq)]
+pub struct MtuMatch<'a> {
+    /// link type, e.g. "DSL" or "generic tunnel or VPN".
+    pub link: &'a str,
+    /// MTU derived from the maximum segment size.
+    pub mtu: u16,
+}
+
+#[derive(Clone, Debug, PartialEq)]
+pub struct HttpMatch<'a> {
+    pub label: &'a Label,
+    pub sig: &'a http::Signature,
+    pub quality: MatchQuality,
+    /// the 'User-Agent' or 'Server' doesn't contain the expected software.
+    pub dishonest: bool,
+}
+
+/// Consistency between the OS claimed by the 'User-Agent' and the TCP fingerprint.
+#[derive(Clone, Copy, Debug, PartialEq)]
+pub enum UserAgentOs<'a> {
+    /// the 'User-Agent' claims the OS of the TCP fingerprint.
+    Consistent,
+    /// the 'User-Agent' claims another OS.
+    Dishonest { claimed: &'a str },
+    /// the 'User-Agent' doesn't claim any OS from the `ua_os` mapping,
+    /// or the TCP fingerprint isn't an OS.
+    Unknown,
+}
+
 #[cfg(feature = "parse")]
 impl Default for Database {
     fn default() -> Self {
@@ -0,0 +1,637 @@
+use std::collections::VecDeque;
+use std::net::{IpAddr, SocketAddr};
+use std::time::SystemTime;
+
+use failure::Error;
+
+use crate::{
+    db::{Database, Direction},
+    flow::{FlowTracker, HttpFingerprint, HttpHeaders, TcpFingerprint},
+    host::{Host, HostCache, HostSnapshot},
+    packet::{Datalink, Reassembler, Segment, Unsupported},
+    uptime::Uptime,
+};
+
+/// Something learned about the client or the server of a TCP connection.
+#[derive(Clone, Debug, PartialEq)]
+pub struct Event {
+    /// capture time of the packet that triggered the event.
+    pub ts: SystemTime,
+    pub client: SocketAddr,
+    pub server: SocketAddr,
+    /// the host the event is about, `Request` for the client and `Response` for the server.
+    pub subject: Direction,
+    pub kind: EventKind,
+}
+
+#[derive(Clone, Debug, PartialEq)]
+pub enum EventKind {
+    /// SYN from the client.
+    Syn(TcpFingerprint),
+    /// SYN+ACK from the server.
+    SynAck(TcpFingerprint),
+    /// MTU of the link, from the maximum segment size of the handshake.
+    Mtu {
+        /// link type, e.g. "DSL", if the MTU is known.
+        link: Option<String>,
+        mtu: u16,
+    },
+    /// uptime, from the TCP timestamps of the handshakes.
+    Uptime(Uptime),
+    HttpRequest(HttpFingerprint),
+    HttpResponse(HttpFingerprint),
+    /// the OS, link type or HTTP software of the host changed, or there is more NAT evidence.
+    HostChange {
+        previous: HostSnapshot,
+        host: Box<Host>,
+    },
+}
+
+/// Fingerprint the hosts from the captured packets, the way p0f does.
+///
+/// The TCP handshakes and the HTTP headers are reported as soon as they are seen.
+#[derive(Debug)]
+pub struct Fingerprinter {
+    db: Database,
+    datalink: Datalink,
+    reassembler: Reassembler,
+    flows: FlowTracker,
+    hosts: HostCache,
+}
+
+impl Fingerprinter {
+    pub fn new(db: Database) -> Self {
+        Fingerprinter {
+            db,
+            datalink: Datalink::Ethernet,
+            reassembler: Reassembler::new().with_any_segment(true),
+            flows: FlowTracker::new(),
+            hosts: HostCache::new(),
+        }
+    }
+
+    /// Link-layer header type of the captured packets, Ethernet by default.
+    pub fn with_datalink(mut self, datalink: Datalink) -> Self {
+        self.datalink = datalink;
+        self
+    }
+
+    pub fn with_flow_tracker(mut self, flows: FlowTracker) -> Self {
+        self.flows = flows;
+        self
+    }
+
+    pub fn with_host_cache(mut self, hosts: HostCache) -> Self {
+        self.hosts = hosts;
+        self
+    }
+
+    pub fn database(&self) -> &Database {
+        &self.db
+    }
+
+    pub fn hosts(&self) -> &HostCache {
+        &self.hosts
+    }
+
+    /// Process a captured packet, returns the events it triggered.
+    pub fn process(&mut self, packet: &[u8], ts: SystemTime) -> Result<Vec<Event>, Error> {
+        let mut events = vec![];
+
+        self.process_with(packet, ts, |event| events.push(event))?;
+
+        Ok(events)
+    }
+
+    /// Process a captured packet, calling back with each event it triggered.
+    ///
+    /// Packets without a TCP segment, e.g. ARP or UDP, are skipped, malformed ones are an error.
+    pub fn process_with<F>(&mut self, packet: &[u8], ts: SystemTime, mut f: F) -> Result<(), Error>
+    where
+        F: FnMut(Event),
+    {
+        let (sig, obs) = match self.reassembler.extract(self.datalink, packet, ts) {
+            Ok(Some(res)) => res,
+            // waiting for the other fragments
+            Ok(None) => return Ok(()),
+            // not TCP, nothing to learn from it
+            Err(ref err) if err.downcast_ref::<Unsupported>().is_some() => return Ok(()),
+            Err(err) => return Err(err),
+        };
+
+        // matched once, for both the event and the flow record
+        let fingerprint = obs
+            .direction()
+            .map(|direction| TcpFingerprint::new(&self.db, sig.clone(), obs.clone(), direction));
+
+        self.hosts.expire(ts);
+
+        if let Some(ref fingerprint) = fingerprint {
+            self.handshake(fingerprint, ts, &mut f);
+        }
+
+        // the completed flows only pair what was already reported
+        let (_, headers) = self.flows.track_with(&self.db, sig, obs, fingerprint, ts);
+
+        if let Some(headers) = headers {
+            self.http(headers, ts, &mut f);
+        }
+
+        Ok(())
+    }
+
+    /// Process the captured packets, and iterate over the events they triggered.
+    pub fn events<I, P>(&mut self, packets: I) -> Events<'_, I::IntoIter>
+    where
+        I: IntoIterator<Item = (P, SystemTime)>,
+        P: AsRef<[u8]>,
+    {
+        Events {
+            fingerprinter: self,
+            packets: packets.into_iter(),
+            pending: VecDeque::new(),
+        }
+    }
+
+    fn handshake<F>(&mut self, fingerprint: &TcpFingerprint, ts: SystemTime, f: &mut F)
+    where
+        F: FnMut(Event),
+    {
+        let sig = &fingerprint.sig;
+        let obs = &fingerprint.obs;
+        let (client, server, subject) = match obs.segment() {
+            Segment::Syn => (
+                SocketAddr::new(obs.src, obs.src_port),
+                SocketAddr::new(obs.dst, obs.dst_port),
+                Direction::Request,
+            ),
+            _ => (
+                SocketAddr::new(obs.dst, obs.dst_port),
+                SocketAddr::new(obs.src, obs.src_port),
+                Direction::Response,
+            ),
+        };
+        let event = |kind| Event {
+            ts,
+            client,
+            server,
+            subject,
+            kind,
+        };
+        let previous = self.hosts.get(&obs.src).map(Host::snapshot);
+
+        self.hosts.update_tcp_fingerprint(obs.src, fingerprint, ts);
+
+        f(event(match subject {
+            Direction::Request => EventKind::Syn(fingerprint.clone()),
+            Direction::Response => EventKind::SynAck(fingerprint.clone()),
+        }));
+
+        if let Some(mtu) = sig.mtu() {
+            let m = self.db.match_mtu(sig);
+
+            if let Some(ref m) = m {
+                self.hosts.update_mtu(obs.src, m, ts);
+            }
+
+            f(event(EventKind::Mtu {
+                link: m.map(|m| m.link.to_owned()),
+                mtu,
+            }));
+        }
+
+        if let Some(tsval) = obs.tsval {
+            if let Some(uptime) = self.hosts.update_timestamp(obs.src, tsval, ts) {
+                f(event(EventKind::Uptime(uptime.clone())));
+            }
+        }
+
+        if let Some(kind) = self.host_change(previous, obs.src) {
+            f(event(kind));
+        }
+    }
+
+    fn http<F>(&mut self, headers: HttpHeaders, ts: SystemTime, f: &mut F)
+    where
+        F: FnMut(Event),
+    {
+        let HttpHeaders {
+            client,
+            server,
+            direction: subject,
+            fingerprint,
+        } = headers;
+        let addr = match subject {
+            Direction::Request => client.ip(),
+            Direction::Response => server.ip(),
+        };
+        let previous = self.hosts.get(&addr).map(Host::snapshot);
+
+        self.hosts.update_http_fingerprint(addr, &fingerprint, ts);
+
+        if let Some(skew) = fingerprint.clock_skew {
+            self.hosts.update_clock_skew(addr, skew, ts);
+        }
+        self.hosts
+            .update_languages(addr, &fingerprint.obs.languages, ts);
+
+        let mut kinds = vec![match subject {
+            Direction::Request => EventKind::HttpRequest(fingerprint),
+            Direction::Response => EventKind::HttpResponse(fingerprint),
+        }];
+
+        kinds.extend(self.host_change(previous, addr));
+
+        for kind in kinds {
+            f(Event {
+                ts,
+                client,
+                server,
+                subject,
+                kind,
+            });
+        }
+    }
+
+    /// Compare the host with its state before the last updates.
+    fn host_change(&self, previous: Option<HostSnapshot>, addr: IpAddr) -> Option<EventKind> {
+        let previous = previous?;
+        let host = self.hosts.get(&addr)?;
+
+        let changed = matches!((&previous.os, &host.os), (Some(a), Some(b)) if *a != b.label)
+            || matches!((&previous.link, &host.link), (Some(a), Some(b)) if a != b)
+            || matches!((&previous.http, &host.http), (Some(a), Some(b)) if a != b)
+            || previous.nat.score < host.nat.score;
+
+        if changed {
+            Some(EventKind::HostChange {
+                previous,
+                host: Box::new(host.clone()),
+            })
+        } else {
+            None
+        }
+    }
+}
+
+/// Iterator over the events of the captured packets, see `Fingerprinter::events`.
+pub struct Events<'a, I> {
+    fingerprinter: &'a mut Fingerprinter,
+    packets: I,
+    pending: VecDeque<Event>,
+}
+
+impl<'a, I, P> Iterator for Events<'a, I>
+where
+    I: Iterator<Item = (P, SystemTime)>,
+    P: AsRef<[u8]>,
+{
+    type Item = Result<Event, Error>;
+
+    fn next(&mut self) -> Option<Self::Item> {
+        loop {
+            if let Some(event) = self.pending.pop_front() {
+                return Some(Ok(event));
+            }
+
+            let (packet, ts) = self.packets.next()?;
+            let pending = &mut self.pending;
+
+            if let Err(err) = self
+                .fingerprinter
+                .process_with(packet.as_ref(), ts, |event| pending.push_back(event))
+            {
+                return Some(Err(err));
+            }
+        }
+    }
+}
+
+#[cfg(all(test, feature = "parse"))]
+mod tests {
+    use std::time::Duration;
+
+    use super::*;
+
+    const DATABASE: &str = r#"
+[mtu]
+
+label = Ethernet or modem
+sig   = 1500
+
+[tcp:request]
+
+label = s:unix:Linux:3.11 and newer
+sig   = *:64:0:*:mss*20,10:mss,sok,ts,nop,ws:df,id+:0
+
+label = s:win:Windows:7 or 8
+sig   = *:128:0:*:8192,8:mss,nop,ws,nop,nop,sok:df,id+:0
+
+[tcp:response]
+
+label = s:unix:Linux:3.x
+sig   = *:64:0:*:mss*10,0:mss:df:0
+
+[http:request]
+
+label = s:!:wget:
+sig   = *:User-Agent,Accept=[*/*],Host,Connection=[Keep-Alive]:Accept-Encoding,Accept-Language,Accept-Charset,Cookie:Wget/
+"#;
+
+    const SYN_OPTIONS: &[u8] = &[
+        0x02, 0x04, 0x05, 0xb4, 0x04, 0x02, 0x08, 0x0a, 0x00, 0x9a, 0x3f, 0x9c, //
+        0x00, 0x00, 0x00, 0x00, 0x01, 0x03, 0x03, 0x0a,
+    ];
+
+    const LATER_SYN_OPTIONS: &[u8] = &[
+        0x02, 0x04, 0x05, 0xb4, 0x04, 0x02, 0x08, 0x0a, 0x00, 0x9a, 0x47, 0x6c, //
+        0x00, 0x00, 0x00, 0x00, 0x01, 0x03, 0x03, 0x0a,
+    ];
+
+    const WINDOWS_SYN_OPTIONS: &[u8] = &[
+        0x02, 0x04, 0x05, 0xb4, 0x01, 0x03, 0x03, 0x08, 0x01, 0x01, 0x04, 0x02,
+    ];
+
+    const SYN_ACK_OPTIONS: &[u8] = &[0x02, 0x04, 0x05, 0xb4];
+
+    const REQUEST: &[u8] = b"GET / HTTP/1.1\r\n\
+        User-Agent: Wget/1.20.3 (linux-gnu)\r\n\
+        Accept: */*\r\n\
+        Host: www.example.com\r\n\
+        Connection: Keep-Alive\r\n\
+        \r\n";
+
+    /// IPv4 segment between 192.168.1.2 and 192.168.1.1:80
+    fn segment(
+        from_client: bool,
+        sport: u16,
+        (ttl, flags, window): (u8, u8, u16),
+        (seq, ack): (u32, u32),
+        opts: &[u8],
+        payload: &[u8],
+    ) -> Vec<u8> {
+        let (src, dst, sport, dport) = if from_client {
+            ([192, 168, 1, 2], [192, 168, 1, 1], sport, 80u16)
+        } else {
+            ([192, 168, 1, 1], [192, 168, 1, 2], 80u16, sport)
+        };
+        let len = (20 + 20 + opts.len() + payload.len()) as u16;
+
+        [
+            &[0x45, 0x00][..],
+            &len.to_be_bytes(),
+            &[0x12, 0x34, 0x40, 0x00, ttl, 0x06, 0x00, 0x00],
+            &src,
+            &dst,
+            &sport.to_be_bytes(),
+            &dport.to_be_bytes(),
+            &seq.to_be_bytes(),
+            &ack.to_be_bytes(),
+            &[(5 + opts.len() as u8 / 4) << 4, flags],
+            &window.to_be_bytes(),
+            &[0x00, 0x00, 0x00, 0x00],
+            opts,
+            payload,
+        ]
+        .concat()
+    }
+
+    fn ts(millis: u64) -> SystemTime {
+        SystemTime::UNIX_EPOCH + Duration::from_millis(millis)
+    }
+
+    fn kinds(events: &[Event]) -> Vec<&'static str> {
+        events
+            .iter()
+            .map(|event| match event.kind {
+                EventKind::Syn(_) => "syn",
+                EventKind::SynAck(_) => "syn+ack",
+                EventKind::Mtu { .. } => "mtu",
+                EventKind::Uptime(_) => "uptime",
+                EventKind::HttpRequest(_) => "http request",
+                EventKind::HttpResponse(_) => "http response",
+                EventKind::HostChange { .. } => "host change",
+            })
+            .collect()
+    }
+
+    #[test]
+    fn test_process() {
+        let db = DATABASE.parse::<Database>().unwrap();
+        let mut fingerprinter = Fingerprinter::new(db).with_datalink(Datalink::Raw);
+
+        let events = fingerprinter
+            .process(
+                &segment(true, 54321, (64, 0x02, 29200), (1000, 0), SYN_OPTIONS, b""),
+                ts(0),
+            )
+            .unwrap();
+
+        assert_eq!(kinds(&events), vec!["syn", "mtu"]);
+        assert_eq!(events[0].client, "192.168.1.2:54321".parse().unwrap());
+        assert_eq!(events[0].server, "192.168.1.1:80".parse().unwrap());
+        assert_eq!(events[0].subject, Direction::Request);
+        assert_eq!(
+            events[1].kind,
+            EventKind::Mtu {
+                link: Some("Ethernet or modem".to_owned()),
+                mtu: 1500,
+            }
+        );
+
+        let mut events = vec![];
+
+        for (packet, millis) in &[
+            (
+                segment(
+                    false,
+                    54321,
+                    (64, 0x12, 14600),
+                    (5000, 1001),
+                    SYN_ACK_OPTIONS,
+                    b"",
+                ),
+                10,
+            ),
+            (
+                segment(true, 54321, (64, 0x18, 229), (1001, 5001), b"", REQUEST),
+                20,
+            ),
+            (
+                segment(true, 54321, (64, 0x11, 229), (1001, 5001), b"", b""),
+                30,
+            ),
+        ] {
+            fingerprinter
+                .process_with(packet, ts(*millis), |event| events.push(event))
+                .unwrap();
+        }
+
+        assert_eq!(kinds(&events), vec!["syn+ack", "mtu", "http request"]);
+        assert_eq!(events[0].subject, Direction::Response);
+
+        match events[2].kind {
+            EventKind::HttpRequest(ref http) => {
+                assert_eq!(http.label.as_ref().unwrap().name, "wget");
+            }
+            _ => unreachable!(),
+        }
+
+        let host = fingerprinter
+            .hosts()
+            .get(&"192.168.1.2".parse().unwrap())
+            .unwrap();
+
+        assert_eq!(host.http.as_ref().unwrap().name, "wget");
+        assert_eq!(host.link.as_deref(), Some("Ethernet or modem"));
+    }
+
+    #[test]
+    fn test_process_unsupported() {
+        let db = DATABASE.parse::<Database>().unwrap();
+        let mut fingerprinter = Fingerprinter::new(db).with_datalink(Datalink::Raw);
+
+        for packet in &[
+            // UDP to port 53
+            &[
+                0x45, 0x00, 0x00, 0x1c, 0x12, 0x34, 0x00, 0x00, 0x40, 0x11, 0x00, 0x00, //
+                0xc0, 0xa8, 0x01, 0x02, 0xc0, 0xa8, 0x01, 0x01, //
+                0xd4, 0x31, 0x00, 0x35, 0x00, 0x08, 0x00, 0x00,
+            ][..],
+            // ICMP echo request
+            &[
+                0x45, 0x00, 0x00, 0x1c, 0x12, 0x34, 0x00, 0x00, 0x40, 0x01, 0x00, 0x00, //
+                0xc0, 0xa8, 0x01, 0x02, 0xc0, 0xa8, 0x01, 0x01, //
+                0x08, 0x00, 0x00, 0x00, 0x00, 0x01, 0x00, 0x01,
+            ][..],
+        ] {
+            assert_eq!(fingerprinter.process(packet, ts(0)).unwrap(), vec![]);
+        }
+
+        // truncated TCP header
+        let syn = segment(true, 54321, (64, 0x02, 29200), (1000, 0), SYN_OPTIONS, b"");
+
+        assert!(fingerprinter.process(&syn[..30], ts(0)).is_err());
+    }
+
+    #[test]
+    fn test_events() {
+        let db = DATABASE.parse::<Database>().unwrap();
+        let mut fingerprinter = Fingerprinter::new(db).with_datalink(Datalink::Raw);
+
+        let packets = [
+            (
+                segment(true, 54321, (64, 0x02, 29200), (1000, 0), SYN_OPTIONS, b""),
+                ts(0),
+            ),
+            (
+                segment(true, 54321, (64, 0x18, 229), (1001, 5001), b"", REQUEST),
+                ts(100),
+            ),
+            // a thousand ticks later, with a 1000 Hz clock
+            (
+                segment(
+                    true,
+                    54322,
+                    (64, 0x02, 29200),
+                    (2000, 0),
+                    LATER_SYN_OPTIONS,
+                    b"",
+                ),
+                ts(2000),
+            ),
+            // another OS behind the same address
+            (
+                segment(
+                    true,
+                    54323,
+                    (128, 0x02, 8192),
+                    (3000, 0),
+                    WINDOWS_SYN_OPTIONS,
+                    b"",
+                ),
+                ts(3000),
+            ),
+        ];
+
+        let events = fingerprinter
+            .events(packets.iter().map(|(packet, ts)| (packet, *ts)))
+            .collect::<Result<Vec<_>, _>>()
+            .unwrap();
+
+        assert_eq!(
+            kinds(&events),
+            vec![
+                "syn",
+                "mtu",
+                // as soon as the headers are complete, without an answer from the server
+                "http request",
+                "syn",
+                "mtu",
+                "uptime",
+                "syn",
+                "mtu",
+                "host change",
+            ]
+        );
+        assert_eq!(events[2].ts, ts(100));
+
+        match events[5].kind {
+            EventKind::Uptime(ref uptime) => assert_eq!(uptime.freq, 1000),
+            _ => unreachable!(),
+        }
+
+        match events[8].kind {
+            EventKind::HostChange {
+                ref previous,
+                ref host,
+            } => {
+                assert_eq!(previous.os.as_ref().unwrap().name, "Linux");
+                assert_eq!(host.os.as_ref().unwrap().label.name, "Windows");
+                assert!(host.nat.is_shared());
+            }
+            _ => unreachable!(),
+        }
+    }
+
+    #[test]
+    fn test_stale_uptime_and_expired_hosts() {
+        let db = DATABASE.parse::<Database>().unwrap();
+        let mut fingerprinter = Fingerprinter::new(db)
+            .with_datalink(Datalink::Raw)
+            .with_host_cache(HostCache::new().with_timeout(Duration::from_secs(10)));
+        let mut events = vec![];
+
+        for (opts, sport, millis) in &[
+            (SYN_OPTIONS, 54321, 0),
+            (LATER_SYN_OPTIONS, 54322, 2000),
+            // the timestamp went backwards, nothing new about the uptime
+            (SYN_OPTIONS, 54323, 2010),
+        ] {
+            let syn = segment(true, *sport, (64, 0x02, 29200), (1000, 0), opts, b"");
+
+            events.push(kinds(&fingerprinter.process(&syn, ts(*millis)).unwrap()));
+        }
+
+        assert_eq!(
+            events,
+            vec![
+                vec!["syn", "mtu"],
+                vec!["syn", "mtu", "uptime"],
+                vec!["syn", "mtu", "host change"],
+            ]
+        );
+
+        let client = "192.168.1.2".parse().unwrap();
+
+        assert!(fingerprinter.hosts().get(&client).is_some());
+
+        // a SYN from the other host, once the client timed out
+        let syn = segment(false, 54324, (64, 0x02, 29200), (1000, 0), b"", b"");
+
+        fingerprinter.process(&syn, ts(12010)).unwrap();
+
+        assert!(fingerprinter.hosts().get(&client).is_none());
+        assert_eq!(fingerprinter.hosts().len(), 1);
+    }
+}
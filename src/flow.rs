@@ -0,0 +1,719 @@
+use std::collections::{BTreeMap, HashMap};
+use std::net::SocketAddr;
+use std::time::{Duration, SystemTime};
+
+use failure::Error;
+
+use pnet::packet::tcp::TcpFlags;
+
+use crate::{
+    db::{Database, Direction, MatchQuality},
+    http,
+    packet::{Datalink, HttpObservation, Observation, Segment},
+    proxy::Proxy,
+    tcp, Label,
+};
+
+/// Fingerprint of one end of the TCP handshake.
+#[derive(Clone, Debug, PartialEq)]
+pub struct TcpFingerprint {
+    pub sig: tcp::Signature,
+    pub obs: Observation,
+    /// best matching OS.
+    pub label: Option<Label>,
+    pub quality: Option<MatchQuality>,
+    /// distance to the host, from the matching signature or the observed TTL.
+    pub distance: Option<u8>,
+}
+
+/// Fingerprint of the HTTP request or response headers.
+#[derive(Clone, Debug, PartialEq)]
+pub struct HttpFingerprint {
+    pub sig: http::Signature,
+    pub obs: HttpObservation,
+    /// best matching client or server software.
+    pub label: Option<Label>,
+    pub quality: Option<MatchQuality>,
+    /// the 'User-Agent' or 'Server' doesn't contain the expected software.
+    pub dishonest: bool,
+    /// offset of the sender's clock to the capture time, in seconds.
+    pub clock_skew: Option<i64>,
+    /// the request went through a proxy.
+    pub proxy: Option<Proxy>,
+}
+
+/// HTTP headers fingerprinted from a segment, as soon as they are complete.
+#[derive(Clone, Debug, PartialEq)]
+pub(crate) struct HttpHeaders {
+    pub client: SocketAddr,
+    pub server: SocketAddr,
+    /// `Request` for the headers from the client, `Response` from the server.
+    pub direction: Direction,
+    pub fingerprint: HttpFingerprint,
+}
+
+/// Fingerprints of both ends of a TCP connection.
+#[derive(Clone, Debug, PartialEq)]
+pub struct FlowRecord {
+    pub client: SocketAddr,
+    pub server: SocketAddr,
+    /// time of the first packet of the flow.
+    pub started: SystemTime,
+    /// time of the last packet of the flow.
+    pub last_seen: SystemTime,
+    /// SYN from the client.
+    pub syn: Option<TcpFingerprint>,
+    /// SYN+ACK from the server.
+    pub syn_ack: Option<TcpFingerprint>,
+    pub http_request: Option<HttpFingerprint>,
+    pub http_response: Option<HttpFingerprint>,
+}
+
+/// Track TCP handshakes in progress, and pair the client SYN with the server SYN+ACK
+/// and the HTTP headers in the first payload segments.
+///
+/// A record is emitted once the flow is closed, when both HTTP headers have been seen
+/// or given up on, or when it expired or was evicted to make room for a new flow.
+#[derive(Debug)]
+pub struct FlowTracker {
+    timeout: Duration,
+    max_flows: usize,
+    max_payload: usize,
+    tick: u64,
+    flows: HashMap<FlowKey, Flow>,
+    /// flows by the time of their last packet, the least recently seen first.
+    by_last_seen: BTreeMap<(SystemTime, u64), FlowKey>,
+}
+
+#[derive(Clone, Copy, Debug, PartialEq, Eq, Hash)]
+struct FlowKey {
+    client: SocketAddr,
+    server: SocketAddr,
+}
+
+#[derive(Debug)]
+struct Flow {
+    /// tick of the last packet, orders the flows seen at the same time.
+    tick: u64,
+    record: FlowRecord,
+    request: Stream,
+    response: Stream,
+}
+
+/// Payload of one direction, buffered until the HTTP headers are complete.
+#[derive(Debug, Default)]
+struct Stream {
+    /// expected sequence number of the next in-order segment.
+    next_seq: Option<u32>,
+    buf: Vec<u8>,
+    /// the HTTP headers were extracted, or this is not HTTP.
+    done: bool,
+}
+
+impl Default for FlowTracker {
+    fn default() -> Self {
+        FlowTracker {
+            timeout: Duration::from_secs(30),
+            max_flows: 1000,
+            max_payload: 8192,
+            tick: 0,
+            flows: HashMap::new(),
+            by_last_seen: BTreeMap::new(),
+        }
+    }
+}
+
+impl FlowTracker {
+    pub fn new() -> Self {
+        Self::default()
+    }
+
+    /// Flows without any packet within the timeout are expired.
+    pub fn with_timeout(mut self, timeout: Duration) -> Self {
+        self.timeout = timeout;
+        self
+    }
+
+    /// Maximum number of flows tracked at the same time, the oldest one is evicted first.
+    pub fn with_max_flows(mut self, max_flows: usize) -> Self {
+        self.max_flows = max_flows;
+        self
+    }
+
+    /// Maximum number of payload bytes buffered per direction to find the HTTP headers.
+    pub fn with_max_payload(mut self, max_payload: usize) -> Self {
+        self.max_payload = max_payload;
+        self
+    }
+
+    /// Number of flows being tracked.
+    pub fn len(&self) -> usize {
+        self.flows.len()
+    }
+
+    pub fn is_empty(&self) -> bool {
+        self.flows.is_empty()
+    }
+
+    /// Extract the signature from a captured packet and track it, see `track`.
+    pub fn process(
+        &mut self,
+        db: &Database,
+        datalink: Datalink,
+        packet: &[u8],
+        ts: SystemTime,
+    ) -> Result<Vec<FlowRecord>, Error> {
+        let (sig, obs) = tcp::Signature::observe_any(datalink, packet)?;
+
+        Ok(self.track(db, sig, obs, ts))
+    }
+
+    /// Track an observed TCP segment, returns the flows that completed or expired.
+    pub fn track(
+        &mut self,
+        db: &Database,
+        sig: tcp::Signature,
+        obs: Observation,
+        ts: SystemTime,
+    ) -> Vec<FlowRecord> {
+        self.track_with(db, sig, obs, None, ts).0
+    }
+
+    /// Track an observed TCP segment, with the fingerprint of the handshake segment if
+    /// it was already matched, see `track`.
+    ///
+    /// Also returns the HTTP headers completed by the segment, without waiting for
+    /// the record of their flow.
+    pub(crate) fn track_with(
+        &mut self,
+        db: &Database,
+        sig: tcp::Signature,
+        obs: Observation,
+        fingerprint: Option<TcpFingerprint>,
+        ts: SystemTime,
+    ) -> (Vec<FlowRecord>, Option<HttpHeaders>) {
+        let mut headers = None;
+        let mut records = self.expire(ts);
+
+        let forward = FlowKey {
+            client: SocketAddr::new(obs.src, obs.src_port),
+            server: SocketAddr::new(obs.dst, obs.dst_port),
+        };
+        let reverse = FlowKey {
+            client: forward.server,
+            server: forward.client,
+        };
+        let key = match obs.segment() {
+            Segment::Syn => forward,
+            Segment::SynAck => reverse,
+            Segment::Other if self.flows.contains_key(&forward) => forward,
+            Segment::Other => reverse,
+        };
+
+        match obs.segment() {
+            Segment::Syn => {
+                // a new connection reusing the same ports
+                if matches!(self.flows.get(&key), Some(flow) if flow.record.syn_ack.is_some()) {
+                    records.extend(self.remove(&key));
+                }
+
+                if self.flows.contains_key(&key) {
+                    // retransmitted SYN
+                    self.touch(&key, ts);
+                } else {
+                    records.extend(self.evict());
+
+                    let mut flow = Flow::new(key, ts);
+
+                    flow.request.next_seq = Some(obs.seq.wrapping_add(1));
+                    flow.record.syn =
+                        Some(fingerprint.unwrap_or_else(|| {
+                            TcpFingerprint::new(db, sig, obs, Direction::Request)
+                        }));
+
+                    self.insert(key, flow);
+                }
+            }
+            Segment::SynAck => {
+                if !self.flows.contains_key(&key) {
+                    records.extend(self.evict());
+
+                    self.insert(key, Flow::new(key, ts));
+                } else {
+                    self.touch(&key, ts);
+                }
+
+                let flow = self.flows.get_mut(&key).unwrap();
+
+                if flow.record.syn_ack.is_none() {
+                    flow.request.next_seq = Some(obs.ack);
+                    flow.response.next_seq = Some(obs.seq.wrapping_add(1));
+                    flow.record.syn_ack =
+                        Some(fingerprint.unwrap_or_else(|| {
+                            TcpFingerprint::new(db, sig, obs, Direction::Response)
+                        }));
+                }
+            }
+            Segment::Other => {
+                let closed = (obs.flags & (TcpFlags::FIN | TcpFlags::RST)) != 0;
+                let max_payload = self.max_payload;
+
+                self.touch(&key, ts);
+
+                let done = match self.flows.get_mut(&key) {
+                    Some(flow) => {
+                        let (stream, direction) = if key == forward {
+                            (&mut flow.request, Direction::Request)
+                        } else {
+                            (&mut flow.response, Direction::Response)
+                        };
+
+                        if let Some((sig, http)) = stream.push(&obs, max_payload) {
+                            let fingerprint = HttpFingerprint::new(db, sig, http, direction, ts);
+
+                            match direction {
+                                Direction::Request => {
+                                    flow.record.http_request = Some(fingerprint.clone())
+                                }
+                                Direction::Response => {
+                                    flow.record.http_response = Some(fingerprint.clone())
+                                }
+                            }
+
+                            headers = Some(HttpHeaders {
+                                client: key.client,
+                                server: key.server,
+                                direction,
+                                fingerprint,
+                            });
+                        }
+
+                        closed || (flow.request.done && flow.response.done)
+                    }
+                    None => false,
+                };
+
+                if done {
+                    records.extend(self.remove(&key));
+                }
+            }
+        }
+
+        (records, headers)
+    }
+
+    /// Drop the flows without any packet within the timeout, returns their records.
+    pub fn expire(&mut self, ts: SystemTime) -> Vec<FlowRecord> {
+        let mut expired = vec![];
+
+        while let Some((&(last_seen, _), &key)) = self.by_last_seen.iter().next() {
+            let is_expired = ts
+                .duration_since(last_seen)
+                .map(|age| age >= self.timeout)
+                .unwrap_or(false);
+
+            if !is_expired {
+                break;
+            }
+
+            expired.extend(self.remove(&key));
+        }
+
+        expired.sort_by_key(|record| record.started);
+        expired
+    }
+
+    /// Drop all the flows, returns their records.
+    pub fn flush(&mut self) -> Vec<FlowRecord> {
+        self.by_last_seen.clear();
+
+        let mut records = self
+            .flows
+            .drain()
+            .map(|(_, flow)| flow.record)
+            .collect::<Vec<_>>();
+
+        records.sort_by_key(|record| record.started);
+        records
+    }
+
+    fn insert(&mut self, key: FlowKey, mut flow: Flow) {
+        self.tick += 1;
+        flow.tick = self.tick;
+
+        self.by_last_seen
+            .insert((flow.record.last_seen, flow.tick), key);
+        self.flows.insert(key, flow);
+    }
+
+    fn remove(&mut self, key: &FlowKey) -> Option<FlowRecord> {
+        let flow = self.flows.remove(key)?;
+
+        self.by_last_seen
+            .remove(&(flow.record.last_seen, flow.tick));
+
+        Some(flow.record)
+    }
+
+    fn touch(&mut self, key: &FlowKey, ts: SystemTime) {
+        if let Some(flow) = self.flows.get_mut(key) {
+            self.by_last_seen
+                .remove(&(flow.record.last_seen, flow.tick));
+            self.tick += 1;
+
+            flow.record.last_seen = ts;
+            flow.tick = self.tick;
+
+            self.by_last_seen.insert((ts, flow.tick), *key);
+        }
+    }
+
+    /// Evict the oldest flow if there is no room for a new one.
+    fn evict(&mut self) -> Option<FlowRecord> {
+        if self.flows.len() < self.max_flows {
+            return None;
+        }
+
+        let key = *self.by_last_seen.values().next()?;
+
+        self.remove(&key)
+    }
+}
+
+impl Flow {
+    fn new(key: FlowKey, ts: SystemTime) -> Self {
+        Flow {
+            tick: 0,
+            record: FlowRecord {
+                client: key.client,
+                server: key.server,
+                started: ts,
+                last_seen: ts,
+                syn: None,
+                syn_ack: None,
+                http_request: None,
+                http_response: None,
+            },
+            request: Stream::default(),
+            response: Stream::default(),
+        }
+    }
+}
+
+impl Stream {
+    /// Append the in-order payload, returns the HTTP signature once the headers are complete.
+    fn push(
+        &mut self,
+        obs: &Observation,
+        max_payload: usize,
+    ) -> Option<(http::Signature, HttpObservation)> {
+        if self.done || obs.payload.is_empty() {
+            return None;
+        }
+
+        match self.next_seq {
+            Some(seq) if seq != obs.seq => return None,
+            _ => self.next_seq = Some(obs.seq.wrapping_add(obs.payload.len() as u32)),
+        }
+
+        let len = obs.payload.len().min(max_payload - self.buf.len());
+
+        self.buf.extend_from_slice(&obs.payload[..len]);
+
+        match http::Signature::observe(&self.buf) {
+            Ok(headers) => {
+                self.done = true;
+                self.buf = vec![];
+
+                Some(headers)
+            }
+            Err(_) => {
+                // give up once the headers are complete, too large or obviously not HTTP
+                if self.buf.len() >= max_payload
+                    || !self.buf[0].is_ascii_uppercase()
+                    || self.buf.windows(2).any(|w| w == b"\n\n")
+                    || self.buf.windows(3).any(|w| w == b"\n\r\n")
+                {
+                    self.done = true;
+                    self.buf = vec![];
+                }
+
+                None
+            }
+        }
+    }
+}
+
+impl TcpFingerprint {
+    pub(crate) fn new(
+        db: &Database,
+        sig: tcp::Signature,
+        obs: Observation,
+        direction: Direction,
+    ) -> Self {
+        let m = db.match_tcp(&sig, direction);
+
+        TcpFingerprint {
+            label: m.as_ref().map(|m| m.label.clone()),
+            quality: m.as_ref().map(|m| m.quality),
+            distance: m
+                .as_ref()
+                .map(|m| m.distance)
+                .or_else(|| sig.ittl.distance()),
+            sig,
+            obs,
+        }
+    }
+}
+
+impl HttpFingerprint {
+    fn new(
+        db: &Database,
+        sig: http::Signature,
+        obs: HttpObservation,
+        direction: Direction,
+        ts: SystemTime,
+    ) -> Self {
+        let m = db.match_http(&sig, direction);
+
+        HttpFingerprint {
+            label: m.as_ref().map(|m| m.label.clone()),
+            quality: m.as_ref().map(|m| m.quality),
+            dishonest: m.as_ref().map(|m| m.dishonest).unwrap_or_default(),
+            clock_skew: obs.clock_skew(ts),
+            proxy: match direction {
+                Direction::Request => Proxy::detect(&sig, &obs, m.as_ref()),
+                Direction::Response => None,
+            },
+            sig,
+            obs,
+        }
+    }
+}
+
+#[cfg(all(test, feature = "parse"))]
+mod tests {
+    use super::*;
+
+    const DATABASE: &str = r#"
+[tcp:request]
+
+label = s:unix:Linux:3.11 and newer
+sig   = *:64:0:*:mss*20,10:mss,sok,ts,nop,ws:df,id+:0
+
+[tcp:response]
+
+label = s:unix:Linux:3.x
+sig   = *:64:0:*:mss*10,0:mss:df:0
+
+[http:request]
+
+label = s:!:wget:
+sig   = *:User-Agent,Accept=[*/*],Host,Connection=[Keep-Alive]:Accept-Encoding,Accept-Language,Accept-Charset,Cookie:Wget/
+"#;
+
+    const SYN_OPTIONS: &[u8] = &[
+        0x02, 0x04, 0x05, 0xb4, 0x04, 0x02, 0x08, 0x0a, 0x00, 0x9a, 0x3f, 0x9c, //
+        0x00, 0x00, 0x00, 0x00, 0x01, 0x03, 0x03, 0x0a,
+    ];
+
+    const SYN_ACK_OPTIONS: &[u8] = &[0x02, 0x04, 0x05, 0xb4];
+
+    /// IPv4 segment between 192.168.1.2:54321 and 192.168.1.1:80
+    fn segment(
+        from_client: bool,
+        flags: u8,
+        seq: u32,
+        ack: u32,
+        window: u16,
+        opts: &[u8],
+        payload: &[u8],
+    ) -> Vec<u8> {
+        let (src, dst, sport, dport) = if from_client {
+            ([192, 168, 1, 2], [192, 168, 1, 1], 54321u16, 80u16)
+        } else {
+            ([192, 168, 1, 1], [192, 168, 1, 2], 80, 54321)
+        };
+        let id: &[u8] = if from_client { &[0x1c, 0x46] } else { &[0, 0] };
+        let len = (40 + opts.len() + payload.len()) as u16;
+
+        [
+            &[0x45, 0x00][..],
+            &len.to_be_bytes(),
+            id,
+            &[0x40, 0x00, 0x40, 0x06, 0x00, 0x00],
+            &src,
+            &dst,
+            &sport.to_be_bytes(),
+            &dport.to_be_bytes(),
+            &seq.to_be_bytes(),
+            &ack.to_be_bytes(),
+            &[(5 + opts.len() as u8 / 4) << 4, flags],
+            &window.to_be_bytes(),
+            &[0x00, 0x00, 0x00, 0x00],
+            opts,
+            payload,
+        ]
+        .concat()
+    }
+
+    fn ts(secs: u64) -> SystemTime {
+        SystemTime::UNIX_EPOCH + Duration::from_secs(secs)
+    }
+
+    const REQUEST: &[u8] = b"GET / HTTP/1.1\r\n\
+        User-Agent: Wget/1.20.3 (linux-gnu)\r\n\
+        Accept: */*\r\n\
+        Host: www.example.com\r\n\
+        Connection: Keep-Alive\r\n\
+        \r\n";
+
+    const RESPONSE: &[u8] = b"HTTP/1.1 200 OK\r\n\
+        Date: Mon, 01 Jan 2018 00:00:00 GMT\r\n\
+        Server: Apache/2.4.29 (Ubuntu)\r\n\
+        Content-Type: text/html\r\n\
+        \r\n";
+
+    #[test]
+    fn test_track() {
+        let db = DATABASE.parse::<Database>().unwrap();
+        let mut tracker = FlowTracker::new();
+
+        let (head, tail) = REQUEST.split_at(20);
+
+        for (packet, secs) in &[
+            (segment(true, 0x02, 1000, 0, 29200, SYN_OPTIONS, b""), 0),
+            // retransmitted SYN
+            (segment(true, 0x02, 1000, 0, 29200, SYN_OPTIONS, b""), 1),
+            (
+                segment(false, 0x12, 5000, 1001, 14600, SYN_ACK_OPTIONS, b""),
+                1,
+            ),
+            (segment(true, 0x10, 1001, 5001, 229, b"", b""), 1),
+            (segment(true, 0x18, 1001, 5001, 229, b"", head), 1),
+            // out of order segment is ignored
+            (segment(true, 0x18, 1041, 5001, 229, b"", b"garbage"), 1),
+            (segment(true, 0x18, 1021, 5001, 229, b"", tail), 1),
+        ] {
+            let records = tracker
+                .process(&db, Datalink::Raw, packet, ts(*secs))
+                .unwrap();
+
+            assert!(records.is_empty());
+        }
+
+        assert_eq!(tracker.len(), 1);
+
+        let records = tracker
+            .process(
+                &db,
+                Datalink::Raw,
+                &segment(
+                    false,
+                    0x18,
+                    5001,
+                    1001 + REQUEST.len() as u32,
+                    235,
+                    b"",
+                    RESPONSE,
+                ),
+                ts(2),
+            )
+            .unwrap();
+
+        assert!(tracker.is_empty());
+        assert_eq!(records.len(), 1);
+
+        let record = &records[0];
+
+        assert_eq!(record.client, "192.168.1.2:54321".parse().unwrap());
+        assert_eq!(record.server, "192.168.1.1:80".parse().unwrap());
+        assert_eq!(record.started, ts(0));
+        assert_eq!(record.last_seen, ts(2));
+
+        let syn = record.syn.as_ref().unwrap();
+
+        assert_eq!(syn.label.as_ref().unwrap().name, "Linux");
+        assert_eq!(syn.quality, Some(MatchQuality::Exact));
+        assert_eq!(syn.distance, Some(0));
+
+        let syn_ack = record.syn_ack.as_ref().unwrap();
+
+        assert_eq!(
+            syn_ack.label.as_ref().unwrap().flavor.as_deref(),
+            Some("3.x")
+        );
+
+        let request = record.http_request.as_ref().unwrap();
+
+        assert_eq!(request.label.as_ref().unwrap().name, "wget");
+        assert_eq!(request.sig.expsw, "Wget/1.20.3 (linux-gnu)");
+        assert!(!request.dishonest);
+        assert_eq!(request.clock_skew, None);
+        assert_eq!(request.proxy, None);
+
+        let response = record.http_response.as_ref().unwrap();
+
+        assert_eq!(response.label, None);
+        assert_eq!(response.sig.expsw, "Apache/2.4.29 (Ubuntu)");
+        // the capture is timestamped near the epoch
+        assert_eq!(response.clock_skew, Some(1_514_764_798));
+    }
+
+    #[test]
+    fn test_close_and_expire() {
+        let db = DATABASE.parse::<Database>().unwrap();
+        let mut tracker = FlowTracker::new()
+            .with_timeout(Duration::from_secs(10))
+            .with_max_flows(1);
+
+        let syn = segment(true, 0x02, 1000, 0, 29200, SYN_OPTIONS, b"");
+
+        // closed by the server
+        assert!(tracker
+            .process(&db, Datalink::Raw, &syn, ts(0))
+            .unwrap()
+            .is_empty());
+
+        let records = tracker
+            .process(
+                &db,
+                Datalink::Raw,
+                &segment(false, 0x14, 0, 1001, 0, b"", b""),
+                ts(0),
+            )
+            .unwrap();
+
+        assert_eq!(records.len(), 1);
+        assert!(records[0].syn.is_some());
+        assert!(records[0].syn_ack.is_none());
+
+        // expired
+        assert!(tracker
+            .process(&db, Datalink::Raw, &syn, ts(0))
+            .unwrap()
+            .is_empty());
+        assert_eq!(tracker.expire(ts(9)), vec![]);
+        assert_eq!(tracker.expire(ts(10)).len(), 1);
+        assert!(tracker.by_last_seen.is_empty());
+
+        // evicted by a new flow
+        let mut other = syn.clone();
+        other[21] = 0x32;
+
+        assert!(tracker
+            .process(&db, Datalink::Raw, &syn, ts(0))
+            .unwrap()
+            .is_empty());
+
+        let records = tracker.process(&db, Datalink::Raw, &other, ts(1)).unwrap();
+
+        assert_eq!(records.len(), 1);
+        assert_eq!(records[0].client, "192.168.1.2:54321".parse().unwrap());
+        assert_eq!(tracker.len(), 1);
+        assert_eq!(tracker.by_last_seen.len(), 1);
+        assert_eq!(tracker.flush().len(), 1);
+        assert!(tracker.is_empty());
+        assert!(tracker.by_last_seen.is_empty());
+    }
+}
@@ -0,0 +1,649 @@
+use std::collections::{BTreeMap, HashMap};
+use std::net::IpAddr;
+use std::time::{Duration, SystemTime};
+
+use crate::{
+    db::{HttpMatch, MatchQuality, MtuMatch, TcpMatch},
+    nat::{self, Nat, NatReason},
+    tcp,
+    uptime::{Sample, Uptime, MAX_TWAIT, MIN_TWAIT},
+    Label,
+};
+
+#[cfg(feature = "packet")]
+use crate::flow::{HttpFingerprint, TcpFingerprint};
+
+/// Maximum number of previous OS matches remembered per host.
+const MAX_OS_HISTORY: usize = 8;
+
+/// Clock skew from which the host clock is considered wrong, in seconds.
+pub const MAX_CLOCK_SKEW: i64 = 10 * 60;
+
+/// What is known about a host, from all the traffic seen from it.
+#[derive(Clone, Debug, PartialEq)]
+pub struct Host {
+    pub addr: IpAddr,
+    pub first_seen: SystemTime,
+    pub last_seen: SystemTime,
+    /// number of TCP handshakes seen from the host.
+    pub total_conn: u64,
+    /// last OS match.
+    pub os: Option<OsGuess>,
+    /// previous OS matches, oldest first, recorded when the OS changed.
+    pub os_history: Vec<OsGuess>,
+    /// network link type, e.g. "DSL", derived from the MTU.
+    pub link: Option<String>,
+    pub mtu: Option<u16>,
+    /// distance in hops, from the last TCP handshake.
+    pub distance: Option<u8>,
+    /// last HTTP client or server software match.
+    pub http: Option<Label>,
+    /// the 'User-Agent' or 'Server' of the last HTTP match doesn't contain the expected software.
+    pub dishonest: bool,
+    /// reference TCP timestamp for the uptime estimation.
+    pub timestamp: Option<Sample>,
+    /// last uptime estimation.
+    pub uptime: Option<Uptime>,
+    /// languages of the last HTTP request with an 'Accept-Language', most preferred first.
+    pub languages: Vec<String>,
+    /// offset of the host clock to the capture time, in seconds, from the last HTTP 'Date'.
+    pub clock_skew: Option<i64>,
+    /// last TCP signature.
+    pub sig: Option<tcp::Signature>,
+    /// evidence of several hosts sharing the address.
+    pub nat: Nat,
+}
+
+/// What is compared to report a change of the host, see `Host::snapshot`.
+#[derive(Clone, Debug, PartialEq)]
+pub struct HostSnapshot {
+    /// label of the last OS match.
+    pub os: Option<Label>,
+    pub link: Option<String>,
+    pub http: Option<Label>,
+    pub nat: Nat,
+}
+
+#[derive(Clone, Debug, PartialEq)]
+pub struct OsGuess {
+    pub label: Label,
+    pub quality: MatchQuality,
+    /// last time the OS was seen.
+    pub seen: SystemTime,
+}
+
+/// Per-host cache, with least recently seen eviction and expiry.
+#[derive(Debug)]
+pub struct HostCache {
+    timeout: Duration,
+    max_hosts: usize,
+    tick: u64,
+    hosts: HashMap<IpAddr, (u64, Host)>,
+    /// hosts by the time and tick of their last update, the least recently seen first.
+    lru: BTreeMap<(SystemTime, u64), IpAddr>,
+}
+
+impl Default for HostCache {
+    fn default() -> Self {
+        HostCache {
+            timeout: Duration::from_secs(120 * 60),
+            max_hosts: 10000,
+            tick: 0,
+            hosts: HashMap::new(),
+            lru: BTreeMap::new(),
+        }
+    }
+}
+
+impl HostCache {
+    pub fn new() -> Self {
+        Self::default()
+    }
+
+    /// Hosts without any traffic within the timeout are expired.
+    pub fn with_timeout(mut self, timeout: Duration) -> Self {
+        self.timeout = timeout;
+        self
+    }
+
+    /// Maximum number of hosts cached, the least recently seen one is evicted first.
+    pub fn with_max_hosts(mut self, max_hosts: usize) -> Self {
+        self.max_hosts = max_hosts;
+        self
+    }
+
+    pub fn len(&self) -> usize {
+        self.hosts.len()
+    }
+
+    pub fn is_empty(&self) -> bool {
+        self.hosts.is_empty()
+    }
+
+    pub fn get(&self, addr: &IpAddr) -> Option<&Host> {
+        self.hosts.get(addr).map(|(_, host)| host)
+    }
+
+    pub fn iter(&self) -> impl Iterator<Item = &Host> {
+        self.hosts.values().map(|(_, host)| host)
+    }
+
+    /// Update the host from a TCP handshake and its best match, if any.
+    ///
+    /// A change of OS, TCP signature or distance is reported as NAT evidence.
+    pub fn update_tcp(
+        &mut self,
+        addr: IpAddr,
+        sig: &tcp::Signature,
+        m: Option<&TcpMatch<'_>>,
+        ts: SystemTime,
+    ) -> &Host {
+        let distance = m.map(|m| m.distance).or_else(|| sig.ittl.distance());
+
+        self.tcp(addr, sig, m.map(|m| (m.label, m.quality)), distance, ts)
+    }
+
+    fn tcp(
+        &mut self,
+        addr: IpAddr,
+        sig: &tcp::Signature,
+        os: Option<(&Label, MatchQuality)>,
+        distance: Option<u8>,
+        ts: SystemTime,
+    ) -> &Host {
+        let host = self.entry(addr, ts);
+
+        if host.total_conn > 0 {
+            for reason in nat::check_tcp(host, sig, os.map(|(label, _)| label), distance) {
+                host.nat.add(reason);
+            }
+        }
+
+        host.total_conn += 1;
+        host.distance = distance;
+        host.sig = Some(sig.clone());
+
+        if let Some((label, quality)) = os {
+            let guess = OsGuess {
+                label: label.clone(),
+                quality,
+                seen: ts,
+            };
+
+            match host.os {
+                Some(ref mut os) if os.label == guess.label => *os = guess,
+                _ => {
+                    if let Some(os) = host.os.replace(guess) {
+                        if host.os_history.len() >= MAX_OS_HISTORY {
+                            host.os_history.remove(0);
+                        }
+
+                        host.os_history.push(os);
+                    }
+                }
+            }
+        }
+
+        host
+    }
+
+    /// Update the link type of the host from its MTU.
+    pub fn update_mtu(&mut self, addr: IpAddr, m: &MtuMatch<'_>, ts: SystemTime) -> &Host {
+        let host = self.entry(addr, ts);
+
+        if matches!(host.link, Some(ref link) if link != m.link) {
+            host.nat.add(NatReason::Link);
+        }
+
+        host.link = Some(m.link.to_owned());
+        host.mtu = Some(m.mtu);
+
+        host
+    }
+
+    /// Update the host from HTTP headers and their best match.
+    pub fn update_http(&mut self, addr: IpAddr, m: &HttpMatch<'_>, ts: SystemTime) -> &Host {
+        self.http(addr, m.label, m.dishonest, ts)
+    }
+
+    pub(crate) fn http(
+        &mut self,
+        addr: IpAddr,
+        label: &Label,
+        dishonest: bool,
+        ts: SystemTime,
+    ) -> &Host {
+        let host = self.entry(addr, ts);
+
+        if matches!(host.http, Some(ref http) if http.name != label.name) {
+            host.nat.add(NatReason::Http);
+        }
+
+        host.http = Some(label.clone());
+        host.dishonest = dishonest;
+
+        host
+    }
+
+    /// Update the uptime of the host from a TCP timestamp value, returns the estimation
+    /// if it was computed from this timestamp.
+    ///
+    /// The first timestamp is kept as reference, until it's too old or inconsistent
+    /// with the later ones, e.g. after a reboot. An inconsistent timestamp is reported
+    /// as NAT evidence.
+    pub fn update_timestamp(
+        &mut self,
+        addr: IpAddr,
+        tsval: u32,
+        ts: SystemTime,
+    ) -> Option<&Uptime> {
+        let host = self.entry(addr, ts);
+        let sample = Sample { tsval, seen: ts };
+
+        // some hosts always send a zero timestamp
+        if tsval == 0 {
+            return None;
+        }
+
+        match host.timestamp {
+            Some(first) => {
+                let elapsed = ts.duration_since(first.seen).unwrap_or_default();
+
+                if elapsed >= MIN_TWAIT {
+                    match Uptime::estimate(first, sample) {
+                        Some(uptime) => {
+                            host.uptime = Some(uptime);
+
+                            return host.uptime.as_ref();
+                        }
+                        None => {
+                            // unlikely to be a reboot in such a short time
+                            if elapsed <= MAX_TWAIT {
+                                host.nat.add(NatReason::Timestamp);
+                            }
+
+                            host.timestamp = Some(sample);
+                        }
+                    }
+                }
+            }
+            None => host.timestamp = Some(sample),
+        }
+
+        None
+    }
+
+    /// Update the clock skew of the host, from the 'Date' of its HTTP headers.
+    pub fn update_clock_skew(&mut self, addr: IpAddr, skew: i64, ts: SystemTime) -> &Host {
+        let host = self.entry(addr, ts);
+
+        host.clock_skew = Some(skew);
+
+        host
+    }
+
+    /// Update the languages of the host, from the 'Accept-Language' of its HTTP requests.
+    ///
+    /// An empty list doesn't replace the known languages.
+    pub fn update_languages(
+        &mut self,
+        addr: IpAddr,
+        languages: &[String],
+        ts: SystemTime,
+    ) -> &Host {
+        let host = self.entry(addr, ts);
+
+        if !languages.is_empty() {
+            host.languages = languages.to_vec();
+        }
+
+        host
+    }
+
+    /// Drop the hosts without any traffic within the timeout, returns them.
+    pub fn expire(&mut self, ts: SystemTime) -> Vec<Host> {
+        let mut expired = vec![];
+
+        while let Some((&(last_seen, _), &addr)) = self.lru.iter().next() {
+            let is_expired = ts
+                .duration_since(last_seen)
+                .map(|age| age >= self.timeout)
+                .unwrap_or(false);
+
+            if !is_expired {
+                break;
+            }
+
+            expired.extend(self.remove(&addr));
+        }
+
+        expired
+    }
+
+    fn remove(&mut self, addr: &IpAddr) -> Option<Host> {
+        let (tick, host) = self.hosts.remove(addr)?;
+
+        self.lru.remove(&(host.last_seen, tick));
+
+        Some(host)
+    }
+
+    /// Lookup the host, or insert it, and mark it as the most recently updated one.
+    pub(crate) fn entry(&mut self, addr: IpAddr, ts: SystemTime) -> &mut Host {
+        self.tick += 1;
+
+        let tick = self.tick;
+
+        match self.hosts.get_mut(&addr) {
+            Some(&mut (ref mut last, ref mut host)) => {
+                self.lru.remove(&(host.last_seen, *last));
+                *last = tick;
+
+                if ts > host.last_seen {
+                    host.last_seen = ts;
+                }
+            }
+            None => {
+                if self.hosts.len() >= self.max_hosts {
+                    if let Some(&oldest) = self.lru.values().next() {
+                        self.remove(&oldest);
+                    }
+                }
+
+                self.hosts.insert(addr, (tick, Host::new(addr, ts)));
+            }
+        }
+
+        let host = &mut self.hosts.get_mut(&addr).unwrap().1;
+
+        self.lru.insert((host.last_seen, tick), addr);
+
+        host
+    }
+}
+
+#[cfg(feature = "packet")]
+impl HostCache {
+    /// Update the host from a fingerprinted TCP handshake, see `update_tcp`.
+    pub fn update_tcp_fingerprint(
+        &mut self,
+        addr: IpAddr,
+        fingerprint: &TcpFingerprint,
+        ts: SystemTime,
+    ) -> &Host {
+        let os = fingerprint.label.as_ref().zip(fingerprint.quality);
+
+        self.tcp(addr, &fingerprint.sig, os, fingerprint.distance, ts)
+    }
+
+    /// Update the host from fingerprinted HTTP headers, if they matched, see `update_http`.
+    pub fn update_http_fingerprint(
+        &mut self,
+        addr: IpAddr,
+        fingerprint: &HttpFingerprint,
+        ts: SystemTime,
+    ) -> &Host {
+        match fingerprint.label {
+            Some(ref label) => self.http(addr, label, fingerprint.dishonest, ts),
+            None => self.entry(addr, ts),
+        }
+    }
+}
+
+impl Host {
+    pub(crate) fn new(addr: IpAddr, ts: SystemTime) -> Self {
+        Host {
+            addr,
+            first_seen: ts,
+            last_seen: ts,
+            total_conn: 0,
+            os: None,
+            os_history: vec![],
+            link: None,
+            mtu: None,
+            distance: None,
+            http: None,
+            dishonest: false,
+            timestamp: None,
+            uptime: None,
+            languages: vec![],
+            clock_skew: None,
+            sig: None,
+            nat: Nat::default(),
+        }
+    }
+
+    pub fn snapshot(&self) -> HostSnapshot {
+        HostSnapshot {
+            os: self.os.as_ref().map(|os| os.label.clone()),
+            link: self.link.clone(),
+            http: self.http.clone(),
+            nat: self.nat.clone(),
+        }
+    }
+
+    /// The host clock is off by more than `MAX_CLOCK_SKEW`.
+    pub fn is_clock_skewed(&self) -> bool {
+        matches!(self.clock_skew, Some(skew) if skew.abs() > MAX_CLOCK_SKEW)
+    }
+}
+
+#[cfg(all(test, feature = "parse"))]
+mod tests {
+    use super::*;
+    use crate::Type;
+
+    fn ts(secs: u64) -> SystemTime {
+        SystemTime::UNIX_EPOCH + Duration::from_secs(secs)
+    }
+
+    fn label(name: &str, flavor: &str) -> Label {
+        Label {
+            ty: Type::Specified,
+            class: Some("unix".to_owned()),
+            name: name.to_owned(),
+            flavor: Some(flavor.to_owned()),
+        }
+    }
+
+    #[test]
+    fn test_update() {
+        let sig: tcp::Signature = "*:64:0:*:mss*20,10:mss,sok,ts,nop,ws:df,id+:0"
+            .parse()
+            .unwrap();
+        let observed: tcp::Signature = "4:60+4:0:1460:mss*20,10:mss,sok,ts,nop,ws:df,id+:0"
+            .parse()
+            .unwrap();
+        let linux = label("Linux", "3.11 and newer");
+        let freebsd = label("FreeBSD", "9.x or newer");
+        let addr = "192.168.1.2".parse().unwrap();
+        let mut cache = HostCache::new();
+
+        for (label, secs) in &[(&linux, 0), (&linux, 1), (&freebsd, 2)] {
+            cache.update_tcp(
+                addr,
+                &observed,
+                Some(&TcpMatch {
+                    label,
+                    sig: &sig,
+                    quality: MatchQuality::Exact,
+                    distance: 3,
+                }),
+                ts(*secs),
+            );
+        }
+
+        cache.update_tcp(addr, &observed, None, ts(3));
+        cache.update_mtu(
+            addr,
+            &MtuMatch {
+                link: "DSL",
+                mtu: 1492,
+            },
+            ts(3),
+        );
+
+        let host = cache.get(&addr).unwrap();
+
+        assert_eq!(host.first_seen, ts(0));
+        assert_eq!(host.last_seen, ts(3));
+        assert_eq!(host.total_conn, 4);
+        assert_eq!(host.distance, Some(4));
+        assert_eq!(host.os.as_ref().unwrap().label, freebsd);
+        assert_eq!(
+            host.os_history,
+            vec![OsGuess {
+                label: linux,
+                quality: MatchQuality::Exact,
+                seen: ts(1),
+            }]
+        );
+        assert_eq!(host.link.as_deref(), Some("DSL"));
+        assert_eq!(host.mtu, Some(1492));
+        assert_eq!(host.nat.reasons, vec![NatReason::Os]);
+        assert!(host.nat.is_shared());
+    }
+
+    #[test]
+    fn test_nat() {
+        let addr = "192.168.1.2".parse().unwrap();
+        let mut cache = HostCache::new();
+
+        for (sig, secs) in &[
+            ("4:64+0:0:1460:mss*20,10:mss,sok,ts,nop,ws:df,id+:0", 0),
+            // the distance changed by one hop
+            ("4:63+1:0:1460:mss*20,10:mss,sok,ts,nop,ws:df,id+:0", 1),
+        ] {
+            let sig: tcp::Signature = sig.parse().unwrap();
+
+            cache.update_tcp(addr, &sig, None, ts(*secs));
+        }
+
+        assert_eq!(cache.get(&addr).unwrap().nat, Nat::default());
+
+        // another TCP/IP stack, farther
+        let sig: tcp::Signature = "4:125+3:0:1460:8192,8:mss,nop,ws,nop,nop,sok:df,id+:0"
+            .parse()
+            .unwrap();
+
+        cache.update_tcp(addr, &sig, None, ts(2));
+
+        for link in &["Ethernet or modem", "DSL"] {
+            cache.update_mtu(addr, &MtuMatch { link, mtu: 1500 }, ts(3));
+        }
+
+        let nat = &cache.get(&addr).unwrap().nat;
+
+        assert_eq!(
+            nat.reasons,
+            vec![NatReason::Signature, NatReason::Distance, NatReason::Link]
+        );
+        assert_eq!(nat.score, 4);
+        assert!(nat.is_shared());
+    }
+
+    #[test]
+    fn test_update_timestamp() {
+        let addr = "192.168.1.2".parse().unwrap();
+        let mut cache = HostCache::new();
+
+        assert_eq!(cache.update_timestamp(addr, 1_000_000, ts(0)), None);
+        // too soon after the reference
+        assert_eq!(cache.update_timestamp(addr, 1_000_001, ts(0)), None);
+
+        let uptime = cache.update_timestamp(addr, 1_010_000, ts(10)).unwrap();
+
+        assert_eq!(uptime.freq, 1000);
+        assert_eq!(uptime.uptime, Duration::from_secs(1010));
+
+        // rebooted or another host, the last estimation is kept until a new one
+        assert_eq!(cache.update_timestamp(addr, 5000, ts(20)), None);
+        assert_eq!(
+            cache.get(&addr).unwrap().uptime.as_ref().unwrap().uptime,
+            Duration::from_secs(1010)
+        );
+        assert_eq!(
+            cache.get(&addr).unwrap().nat.reasons,
+            vec![NatReason::Timestamp]
+        );
+
+        let uptime = cache.update_timestamp(addr, 15000, ts(30)).unwrap();
+
+        assert_eq!(uptime.uptime, Duration::from_secs(15));
+        assert_eq!(
+            cache.get(&addr).unwrap().timestamp,
+            Some(Sample {
+                tsval: 5000,
+                seen: ts(20)
+            })
+        );
+    }
+
+    #[test]
+    fn test_update_clock_skew() {
+        let addr = "192.168.1.1".parse().unwrap();
+        let mut cache = HostCache::new();
+
+        for (skew, skewed) in &[(3, false), (-MAX_CLOCK_SKEW, false), (-3600, true)] {
+            let host = cache.update_clock_skew(addr, *skew, ts(0));
+
+            assert_eq!(host.clock_skew, Some(*skew));
+            assert_eq!(host.is_clock_skewed(), *skewed);
+        }
+    }
+
+    #[test]
+    fn test_update_languages() {
+        let addr = "192.168.1.2".parse().unwrap();
+        let mut cache = HostCache::new();
+        let languages = vec!["en-US".to_owned(), "en".to_owned()];
+
+        cache.update_languages(addr, &languages, ts(0));
+
+        let host = cache.update_languages(addr, &[], ts(1));
+
+        assert_eq!(host.languages, languages);
+        assert_eq!(host.last_seen, ts(1));
+    }
+
+    #[test]
+    fn test_evict_and_expire() {
+        let mut cache = HostCache::new()
+            .with_max_hosts(2)
+            .with_timeout(Duration::from_secs(10));
+        let addrs = ["10.0.0.1", "10.0.0.2", "10.0.0.3"]
+            .iter()
+            .map(|s| s.parse().unwrap())
+            .collect::<Vec<IpAddr>>();
+
+        let sig: tcp::Signature = "4:64+0:0:1460:mss*20,10:mss,sok,ts,nop,ws:df,id+:0"
+            .parse()
+            .unwrap();
+
+        cache.update_tcp(addrs[0], &sig, None, ts(0));
+        cache.update_tcp(addrs[1], &sig, None, ts(1));
+        // the first host is now the most recently updated one
+        cache.update_tcp(addrs[0], &sig, None, ts(2));
+        cache.update_tcp(addrs[2], &sig, None, ts(3));
+
+        assert_eq!(cache.len(), 2);
+        assert!(cache.get(&addrs[0]).is_some());
+        assert!(cache.get(&addrs[1]).is_none());
+        assert!(cache.get(&addrs[2]).is_some());
+
+        let expired = cache.expire(ts(12));
+
+        assert_eq!(expired.len(), 1);
+        assert_eq!(expired[0].addr, addrs[0]);
+        assert_eq!(cache.len(), 1);
+        assert_eq!(cache.lru.len(), 1);
+
+        // a late packet doesn't make the host look older
+        cache.update_tcp(addrs[2], &sig, None, ts(1));
+
+        assert!(cache.expire(ts(12)).is_empty());
+        assert_eq!(cache.expire(ts(13)).len(), 1);
+        assert!(cache.is_empty());
+        assert!(cache.lru.is_empty());
+    }
+}
@@ -2,15 +2,28 @@
 extern crate log;
 
 pub mod db;
+pub mod host;
 pub mod http;
+pub mod nat;
 pub mod tcp;
+pub mod uptime;
+
+mod matcher;
 
 #[cfg(feature = "display")]
 mod display;
 #[cfg(feature = "packet")]
-mod packet;
+pub mod fingerprinter;
+#[cfg(feature = "packet")]
+pub mod flow;
+#[cfg(all(feature = "packet", feature = "display"))]
+pub mod logger;
+#[cfg(feature = "packet")]
+pub mod packet;
 #[cfg(feature = "parse")]
 mod parse;
+#[cfg(feature = "packet")]
+pub mod proxy;
 
 #[derive(Clone, Debug, PartialEq)]
 pub struct Label {
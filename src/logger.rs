@@ -0,0 +1,737 @@
+use std::fmt;
+use std::io::{self, Write};
+use std::net::SocketAddr;
+use std::time::{SystemTime, UNIX_EPOCH};
+
+use crate::{
+    db::{Direction, MatchQuality},
+    fingerprinter::{Event, EventKind},
+    flow::{HttpFingerprint, TcpFingerprint},
+    matcher::initial_ttl,
+    nat::{Nat, NatReason},
+    tcp::Quirk,
+    Label,
+};
+
+/// Write the events as p0f log lines, e.g.
+///
+/// `[2018/01/01 00:00:00] mod=syn|cli=1.2.3.4/1234|srv=4.3.2.1/80|subj=cli|os=Linux 3.11 and newer|dist=0|params=none|raw_sig=...`
+///
+/// p0f writes the timestamps in local time, the writer in UTC or at a fixed offset
+/// from it, see `with_utc_offset`, which doesn't follow daylight saving time changes.
+#[derive(Debug)]
+pub struct LogWriter<W> {
+    w: W,
+    utc_offset: i32,
+}
+
+impl<W: Write> LogWriter<W> {
+    pub fn new(w: W) -> Self {
+        LogWriter { w, utc_offset: 0 }
+    }
+
+    /// Offset of the timestamps to UTC, in seconds, e.g. the local one to log as p0f does.
+    pub fn with_utc_offset(mut self, utc_offset: i32) -> Self {
+        self.utc_offset = utc_offset;
+        self
+    }
+
+    pub fn write(&mut self, event: &Event) -> io::Result<()> {
+        writeln!(
+            self.w,
+            "{}",
+            LogLine {
+                event,
+                utc_offset: self.utc_offset,
+            }
+        )
+    }
+
+    pub fn flush(&mut self) -> io::Result<()> {
+        self.w.flush()
+    }
+
+    pub fn into_inner(self) -> W {
+        self.w
+    }
+}
+
+/// An event formatted as a p0f log line, without the line feed.
+pub struct LogLine<'a> {
+    pub event: &'a Event,
+    /// offset of the timestamp to UTC, in seconds.
+    pub utc_offset: i32,
+}
+
+impl fmt::Display for LogLine<'_> {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        let event = self.event;
+
+        write!(
+            f,
+            "[{}] mod={}|cli={}|srv={}|subj={}",
+            Timestamp(event.ts, self.utc_offset),
+            module(&event.kind),
+            Addr(event.client),
+            Addr(event.server),
+            match event.subject {
+                Direction::Request => "cli",
+                Direction::Response => "srv",
+            }
+        )?;
+
+        match event.kind {
+            EventKind::Syn(ref tcp) | EventKind::SynAck(ref tcp) => {
+                match tcp.label {
+                    Some(ref label) if label.class.is_none() => write!(f, "|app={}", Name(label))?,
+                    Some(ref label) => write!(f, "|os={}", Name(label))?,
+                    None => f.write_str("|os=???")?,
+                }
+
+                // guessed from the observed TTL when too far away to be matched
+                let distance = tcp
+                    .distance
+                    .unwrap_or_else(|| initial_ttl(tcp.obs.ttl) - tcp.obs.ttl);
+                // the window size as a multiple of the MSS or MTU, as p0f writes it
+                let mut sig = tcp.sig.clone();
+
+                sig.normalize();
+
+                write!(
+                    f,
+                    "|dist={}|params={}|raw_sig={}",
+                    distance,
+                    Params(tcp_params(tcp)),
+                    sig
+                )
+            }
+            EventKind::Mtu { ref link, mtu } => write!(
+                f,
+                "|link={}|raw_mtu={}",
+                link.as_deref().unwrap_or("???"),
+                mtu
+            ),
+            EventKind::Uptime(ref uptime) => {
+                let mins = uptime.uptime.as_secs() / 60;
+
+                write!(
+                    f,
+                    "|uptime={} days {} hrs {} min (modulo {} days)|raw_freq={:.2} Hz",
+                    mins / 60 / 24,
+                    mins / 60 % 24,
+                    mins % 60,
+                    uptime.wraparound.as_secs() / 86400,
+                    uptime.raw_freq
+                )
+            }
+            EventKind::HttpRequest(ref http) | EventKind::HttpResponse(ref http) => {
+                match http.label {
+                    Some(ref label) => write!(f, "|app={}", Name(label))?,
+                    None => f.write_str("|app=???")?,
+                }
+
+                if event.subject == Direction::Request {
+                    write!(
+                        f,
+                        "|lang={}",
+                        http.obs.language().and_then(language).unwrap_or("none")
+                    )?;
+                }
+
+                write!(
+                    f,
+                    "|params={}|raw_sig={}",
+                    Params(http_params(http)),
+                    http.sig
+                )
+            }
+            EventKind::HostChange {
+                ref previous,
+                ref host,
+            } => {
+                // the new evidence, or all of it if it's the same reasons again
+                let mut reasons = host
+                    .nat
+                    .reasons
+                    .iter()
+                    .filter(|reason| !previous.nat.reasons.contains(reason))
+                    .map(|&reason| nat_reason(reason))
+                    .collect::<Vec<_>>();
+
+                if reasons.is_empty() {
+                    reasons = host.nat.reasons.iter().map(|&r| nat_reason(r)).collect();
+                }
+
+                write!(
+                    f,
+                    "|reason={}|raw_hits={}",
+                    Params(reasons),
+                    RawHits(&host.nat)
+                )
+            }
+        }
+    }
+}
+
+fn module(kind: &EventKind) -> &'static str {
+    match kind {
+        EventKind::Syn(_) => "syn",
+        EventKind::SynAck(_) => "syn+ack",
+        EventKind::Mtu { .. } => "mtu",
+        EventKind::Uptime(_) => "uptime",
+        EventKind::HttpRequest(_) => "http request",
+        EventKind::HttpResponse(_) => "http response",
+        EventKind::HostChange { .. } => "host change",
+    }
+}
+
+fn tcp_params(tcp: &TcpFingerprint) -> Vec<&'static str> {
+    let mut params = vec![];
+
+    match tcp.quality {
+        Some(MatchQuality::Generic) => params.push("generic"),
+        Some(MatchQuality::Fuzzy) => params.push("fuzzy"),
+        _ => {}
+    }
+
+    if tcp.sig.quirks.contains(&Quirk::ExcessiveWindowScaling) {
+        params.push("excess_wscale");
+    }
+
+    params
+}
+
+fn http_params(http: &HttpFingerprint) -> Vec<&'static str> {
+    let mut params = vec![];
+
+    if http.quality == Some(MatchQuality::Generic) {
+        params.push("generic");
+    }
+    if http.dishonest {
+        params.push("dishonest");
+    }
+    if http.sig.expsw.is_empty() {
+        params.push("anonymous");
+    }
+
+    params
+}
+
+/// Name of the primary subtag of the language tag, e.g. "English" for "en-US".
+fn language(tag: &str) -> Option<&'static str> {
+    let code = tag.split('-').next()?.to_ascii_lowercase();
+
+    LANGUAGES
+        .binary_search_by_key(&code.as_str(), |&(code, _)| code)
+        .ok()
+        .map(|i| LANGUAGES[i].1)
+}
+
+fn nat_reason(reason: NatReason) -> &'static str {
+    match reason {
+        NatReason::Os => "os",
+        NatReason::Signature => "sig",
+        NatReason::Distance => "dist",
+        NatReason::Timestamp => "tstamp",
+        NatReason::Link => "link",
+        NatReason::Http => "http",
+    }
+}
+
+/// NAT reasons in the order of the p0f 'raw_hits' counters.
+const RAW_HITS: &[NatReason] = &[
+    NatReason::Http,
+    NatReason::Os,
+    NatReason::Signature,
+    NatReason::Timestamp,
+    NatReason::Distance,
+    NatReason::Link,
+];
+
+/// Comma separated hits of each NAT reason, e.g. "0,1,0,0,1,0".
+struct RawHits<'a>(&'a Nat);
+
+impl fmt::Display for RawHits<'_> {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        for (i, &reason) in RAW_HITS.iter().enumerate() {
+            if i > 0 {
+                f.write_str(",")?;
+            }
+
+            write!(f, "{}", self.0.hits(reason))?;
+        }
+
+        Ok(())
+    }
+}
+
+/// Names of the ISO 639-1 language codes, as p0f reports them, sorted by code.
+const LANGUAGES: &[(&str, &str)] = &[
+    ("aa", "Afar"),
+    ("ab", "Abkhazian"),
+    ("ae", "Avestan"),
+    ("af", "Afrikaans"),
+    ("ak", "Akan"),
+    ("am", "Amharic"),
+    ("an", "Aragonese"),
+    ("ar", "Arabic"),
+    ("as", "Assamese"),
+    ("av", "Avaric"),
+    ("ay", "Aymara"),
+    ("az", "Azerbaijani"),
+    ("ba", "Bashkir"),
+    ("be", "Belarusian"),
+    ("bg", "Bulgarian"),
+    ("bh", "Bihari"),
+    ("bi", "Bislama"),
+    ("bm", "Bambara"),
+    ("bn", "Bengali"),
+    ("bo", "Tibetan"),
+    ("br", "Breton"),
+    ("bs", "Bosnian"),
+    ("ca", "Catalan"),
+    ("ce", "Chechen"),
+    ("ch", "Chamorro"),
+    ("co", "Corsican"),
+    ("cr", "Cree"),
+    ("cs", "Czech"),
+    ("cu", "Church Slavic"),
+    ("cv", "Chuvash"),
+    ("cy", "Welsh"),
+    ("da", "Danish"),
+    ("de", "German"),
+    ("dv", "Divehi"),
+    ("dz", "Dzongkha"),
+    ("ee", "Ewe"),
+    ("el", "Greek"),
+    ("en", "English"),
+    ("eo", "Esperanto"),
+    ("es", "Spanish"),
+    ("et", "Estonian"),
+    ("eu", "Basque"),
+    ("fa", "Persian"),
+    ("ff", "Fulah"),
+    ("fi", "Finnish"),
+    ("fj", "Fijian"),
+    ("fo", "Faroese"),
+    ("fr", "French"),
+    ("fy", "Western Frisian"),
+    ("ga", "Irish"),
+    ("gd", "Scottish Gaelic"),
+    ("gl", "Galician"),
+    ("gn", "Guarani"),
+    ("gu", "Gujarati"),
+    ("gv", "Manx"),
+    ("ha", "Hausa"),
+    ("he", "Hebrew"),
+    ("hi", "Hindi"),
+    ("ho", "Hiri Motu"),
+    ("hr", "Croatian"),
+    ("ht", "Haitian"),
+    ("hu", "Hungarian"),
+    ("hy", "Armenian"),
+    ("hz", "Herero"),
+    ("ia", "Interlingua"),
+    ("id", "Indonesian"),
+    ("ie", "Interlingue"),
+    ("ig", "Igbo"),
+    ("ii", "Sichuan Yi"),
+    ("ik", "Inupiaq"),
+    ("io", "Ido"),
+    ("is", "Icelandic"),
+    ("it", "Italian"),
+    ("iu", "Inuktitut"),
+    ("ja", "Japanese"),
+    ("jv", "Javanese"),
+    ("ka", "Georgian"),
+    ("kg", "Kongo"),
+    ("ki", "Kikuyu"),
+    ("kj", "Kuanyama"),
+    ("kk", "Kazakh"),
+    ("kl", "Kalaallisut"),
+    ("km", "Khmer"),
+    ("kn", "Kannada"),
+    ("ko", "Korean"),
+    ("kr", "Kanuri"),
+    ("ks", "Kashmiri"),
+    ("ku", "Kurdish"),
+    ("kv", "Komi"),
+    ("kw", "Cornish"),
+    ("ky", "Kirghiz"),
+    ("la", "Latin"),
+    ("lb", "Luxembourgish"),
+    ("lg", "Ganda"),
+    ("li", "Limburgish"),
+    ("ln", "Lingala"),
+    ("lo", "Lao"),
+    ("lt", "Lithuanian"),
+    ("lu", "Luba-Katanga"),
+    ("lv", "Latvian"),
+    ("mg", "Malagasy"),
+    ("mh", "Marshallese"),
+    ("mi", "Maori"),
+    ("mk", "Macedonian"),
+    ("ml", "Malayalam"),
+    ("mn", "Mongolian"),
+    ("mr", "Marathi"),
+    ("ms", "Malay"),
+    ("mt", "Maltese"),
+    ("my", "Burmese"),
+    ("na", "Nauru"),
+    ("nb", "Norwegian Bokmal"),
+    ("nd", "North Ndebele"),
+    ("ne", "Nepali"),
+    ("ng", "Ndonga"),
+    ("nl", "Dutch"),
+    ("nn", "Norwegian Nynorsk"),
+    ("no", "Norwegian"),
+    ("nr", "South Ndebele"),
+    ("nv", "Navajo"),
+    ("ny", "Chichewa"),
+    ("oc", "Occitan"),
+    ("oj", "Ojibwa"),
+    ("om", "Oromo"),
+    ("or", "Oriya"),
+    ("os", "Ossetian"),
+    ("pa", "Punjabi"),
+    ("pi", "Pali"),
+    ("pl", "Polish"),
+    ("ps", "Pashto"),
+    ("pt", "Portuguese"),
+    ("qu", "Quechua"),
+    ("rm", "Romansh"),
+    ("rn", "Rundi"),
+    ("ro", "Romanian"),
+    ("ru", "Russian"),
+    ("rw", "Kinyarwanda"),
+    ("sa", "Sanskrit"),
+    ("sc", "Sardinian"),
+    ("sd", "Sindhi"),
+    ("se", "Northern Sami"),
+    ("sg", "Sango"),
+    ("si", "Sinhala"),
+    ("sk", "Slovak"),
+    ("sl", "Slovenian"),
+    ("sm", "Samoan"),
+    ("sn", "Shona"),
+    ("so", "Somali"),
+    ("sq", "Albanian"),
+    ("sr", "Serbian"),
+    ("ss", "Swati"),
+    ("st", "Southern Sotho"),
+    ("su", "Sundanese"),
+    ("sv", "Swedish"),
+    ("sw", "Swahili"),
+    ("ta", "Tamil"),
+    ("te", "Telugu"),
+    ("tg", "Tajik"),
+    ("th", "Thai"),
+    ("ti", "Tigrinya"),
+    ("tk", "Turkmen"),
+    ("tl", "Tagalog"),
+    ("tn", "Tswana"),
+    ("to", "Tonga"),
+    ("tr", "Turkish"),
+    ("ts", "Tsonga"),
+    ("tt", "Tatar"),
+    ("tw", "Twi"),
+    ("ty", "Tahitian"),
+    ("ug", "Uighur"),
+    ("uk", "Ukrainian"),
+    ("ur", "Urdu"),
+    ("uz", "Uzbek"),
+    ("ve", "Venda"),
+    ("vi", "Vietnamese"),
+    ("vo", "Volapuk"),
+    ("wa", "Walloon"),
+    ("wo", "Wolof"),
+    ("xh", "Xhosa"),
+    ("yi", "Yiddish"),
+    ("yo", "Yoruba"),
+    ("za", "Zhuang"),
+    ("zh", "Chinese"),
+    ("zu", "Zulu"),
+];
+
+/// Space separated, or "none".
+struct Params(Vec<&'static str>);
+
+impl fmt::Display for Params {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        if self.0.is_empty() {
+            f.write_str("none")
+        } else {
+            f.write_str(&self.0.join(" "))
+        }
+    }
+}
+
+/// Name and flavor of the label, e.g. "Linux 3.11 and newer".
+struct Name<'a>(&'a Label);
+
+impl fmt::Display for Name<'_> {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        f.write_str(&self.0.name)?;
+
+        match self.0.flavor {
+            Some(ref flavor) if !flavor.is_empty() => write!(f, " {}", flavor),
+            _ => Ok(()),
+        }
+    }
+}
+
+/// Address and port, e.g. "1.2.3.4/1234" or "2001:db8::1/80".
+struct Addr(SocketAddr);
+
+impl fmt::Display for Addr {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        write!(f, "{}/{}", self.0.ip(), self.0.port())
+    }
+}
+
+/// Date and time at an offset to UTC, in seconds, e.g. "2018/01/01 00:00:00".
+struct Timestamp(SystemTime, i32);
+
+impl fmt::Display for Timestamp {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        let secs = self
+            .0
+            .duration_since(UNIX_EPOCH)
+            .map(|d| d.as_secs() as i64 + i64::from(self.1))
+            .unwrap_or_default()
+            .max(0) as u64;
+        let (year, month, day) = civil_from_days(secs / 86400);
+
+        write!(
+            f,
+            "{:04}/{:02}/{:02} {:02}:{:02}:{:02}",
+            year,
+            month,
+            day,
+            secs / 3600 % 24,
+            secs / 60 % 60,
+            secs % 60
+        )
+    }
+}
+
+/// Date in the proleptic Gregorian calendar of the days since the Unix epoch.
+fn civil_from_days(days: u64) -> (u64, u64, u64) {
+    let days = days + 719_468;
+    let era = days / 146_097;
+    let doe = days - era * 146_097;
+    let yoe = (doe - doe / 1460 + doe / 36524 - doe / 146_096) / 365;
+    let doy = doe - (365 * yoe + yoe / 4 - yoe / 100);
+    let mp = (5 * doy + 2) / 153;
+    let day = doy - (153 * mp + 2) / 5 + 1;
+    let month = if mp < 10 { mp + 3 } else { mp - 9 };
+    let year = yoe + era * 400 + if month <= 2 { 1 } else { 0 };
+
+    (year, month, day)
+}
+
+#[cfg(all(test, feature = "parse"))]
+mod tests {
+    use std::time::Duration;
+
+    use super::*;
+    use crate::{
+        host::Host,
+        http,
+        packet::{Datalink, HttpObservation, Observation},
+        tcp, Type,
+    };
+
+    /// IPv4 SYN from 1.2.3.4:1234 to 4.3.2.1:80
+    const SYN: &[u8] = &[
+        0x45, 0x00, 0x00, 0x3c, 0x12, 0x34, 0x40, 0x00, 0x40, 0x06, 0x00, 0x00, //
+        0x01, 0x02, 0x03, 0x04, 0x04, 0x03, 0x02, 0x01, //
+        0x04, 0xd2, 0x00, 0x50, 0x00, 0x00, 0x03, 0xe8, 0x00, 0x00, 0x00, 0x00, //
+        0xa0, 0x02, 0x72, 0x10, 0x00, 0x00, 0x00, 0x00, //
+        0x02, 0x04, 0x05, 0xb4, 0x04, 0x02, 0x08, 0x0a, 0x00, 0x9a, 0x3f, 0x9c, //
+        0x00, 0x00, 0x00, 0x00, 0x01, 0x03, 0x03, 0x0a,
+    ];
+
+    fn event(kind: EventKind) -> Event {
+        Event {
+            ts: UNIX_EPOCH + Duration::from_secs(1_514_764_800 + 3723),
+            client: "1.2.3.4:1234".parse().unwrap(),
+            server: "4.3.2.1:80".parse().unwrap(),
+            subject: Direction::Request,
+            kind,
+        }
+    }
+
+    fn label(class: Option<&str>, name: &str, flavor: &str) -> Label {
+        Label {
+            ty: Type::Specified,
+            class: class.map(str::to_owned),
+            name: name.to_owned(),
+            flavor: Some(flavor.to_owned()),
+        }
+    }
+
+    #[test]
+    fn test_log_line() {
+        let (sig, obs) = tcp::Signature::observe(Datalink::Raw, SYN).unwrap();
+        let http_sig = http::Signature::extract(
+            b"GET / HTTP/1.1\r\n\
+            User-Agent: Wget/1.20.3 (linux-gnu)\r\n\
+            Accept: */*\r\n\
+            Host: www.example.com\r\n\
+            \r\n",
+        )
+        .unwrap();
+
+        let mut host = Host::new("1.2.3.4".parse().unwrap(), UNIX_EPOCH);
+        let previous = host.snapshot();
+
+        host.nat.add(NatReason::Os);
+        host.nat.add(NatReason::Distance);
+        host.nat.add(NatReason::Distance);
+
+        for (kind, line) in [
+            (
+                EventKind::Syn(TcpFingerprint {
+                    sig: sig.clone(),
+                    obs: obs.clone(),
+                    label: Some(label(Some("unix"), "Linux", "3.11 and newer")),
+                    quality: Some(MatchQuality::Exact),
+                    distance: Some(0),
+                }),
+                "mod=syn|cli=1.2.3.4/1234|srv=4.3.2.1/80|subj=cli|os=Linux 3.11 and newer|dist=0|params=none|raw_sig=4:64+0:0:1460:mss*20,10:mss,sok,ts,nop,ws:df,id+:0",
+            ),
+            (
+                EventKind::SynAck(TcpFingerprint {
+                    sig: tcp::Signature {
+                        ittl: tcp::TTL::Guess(40),
+                        ..sig
+                    },
+                    obs: Observation {
+                        ttl: 40,
+                        ..obs
+                    },
+                    label: None,
+                    quality: None,
+                    distance: None,
+                }),
+                "mod=syn+ack|cli=1.2.3.4/1234|srv=4.3.2.1/80|subj=cli|os=???|dist=24|params=none|raw_sig=4:40+?:0:1460:mss*20,10:mss,sok,ts,nop,ws:df,id+:0",
+            ),
+            (
+                EventKind::Mtu {
+                    link: None,
+                    mtu: 1234,
+                },
+                "mod=mtu|cli=1.2.3.4/1234|srv=4.3.2.1/80|subj=cli|link=???|raw_mtu=1234",
+            ),
+            (
+                EventKind::Uptime(
+                    crate::uptime::Uptime::estimate(
+                        crate::uptime::Sample {
+                            tsval: 90_000_000,
+                            seen: UNIX_EPOCH,
+                        },
+                        crate::uptime::Sample {
+                            tsval: 90_001_000,
+                            seen: UNIX_EPOCH + Duration::from_secs(1),
+                        },
+                    )
+                    .unwrap(),
+                ),
+                "mod=uptime|cli=1.2.3.4/1234|srv=4.3.2.1/80|subj=cli|uptime=1 days 1 hrs 0 min (modulo 49 days)|raw_freq=1000.00 Hz",
+            ),
+            (
+                EventKind::HttpRequest(HttpFingerprint {
+                    sig: http_sig,
+                    obs: HttpObservation {
+                        languages: vec!["en-US".to_owned()],
+                        ..Default::default()
+                    },
+                    label: Some(label(None, "wget", "")),
+                    quality: Some(MatchQuality::Exact),
+                    dishonest: true,
+                    clock_skew: None,
+                    proxy: None,
+                }),
+                "mod=http request|cli=1.2.3.4/1234|srv=4.3.2.1/80|subj=cli|app=wget|lang=English|params=dishonest|raw_sig=1:User-Agent,Accept=[*/*],Host:Connection,Accept-Encoding,Accept-Language,Accept-Charset,Keep-Alive:Wget/1.20.3 (linux-gnu)",
+            ),
+            (
+                EventKind::HostChange {
+                    previous,
+                    host: Box::new(host),
+                },
+                "mod=host change|cli=1.2.3.4/1234|srv=4.3.2.1/80|subj=cli|reason=os dist|raw_hits=0,1,0,0,2,0",
+            ),
+        ] {
+            assert_eq!(
+                LogLine {
+                    event: &event(kind),
+                    utc_offset: 0,
+                }
+                .to_string(),
+                format!("[2018/01/01 01:02:03] {}", line)
+            );
+        }
+    }
+
+    #[test]
+    fn test_log_writer() {
+        let mut writer = LogWriter::new(vec![]);
+
+        writer
+            .write(&event(EventKind::Mtu {
+                link: Some("DSL".to_owned()),
+                mtu: 1492,
+            }))
+            .unwrap();
+
+        assert_eq!(
+            String::from_utf8(writer.into_inner()).unwrap(),
+            "[2018/01/01 01:02:03] mod=mtu|cli=1.2.3.4/1234|srv=4.3.2.1/80|subj=cli|link=DSL|raw_mtu=1492\n"
+        );
+
+        // e.g. in UTC-08:00
+        let mut writer = LogWriter::new(vec![]).with_utc_offset(-8 * 3600);
+
+        writer
+            .write(&event(EventKind::Mtu {
+                link: Some("DSL".to_owned()),
+                mtu: 1492,
+            }))
+            .unwrap();
+
+        assert!(String::from_utf8(writer.into_inner())
+            .unwrap()
+            .starts_with("[2017/12/31 17:02:03] mod=mtu|"));
+    }
+
+    #[test]
+    fn test_language() {
+        for (tag, name) in &[
+            ("en-US", Some("English")),
+            ("fr", Some("French")),
+            ("DE-ch", Some("German")),
+            ("zu", Some("Zulu")),
+            ("x-klingon", None),
+            ("", None),
+        ] {
+            assert_eq!(language(tag), *name, "{}", tag);
+        }
+    }
+
+    #[test]
+    fn test_timestamp() {
+        for (secs, offset, ts) in &[
+            (0, 0, "1970/01/01 00:00:00"),
+            (951_782_400, 0, "2000/02/29 00:00:00"),
+            (1_709_251_199, 0, "2024/02/29 23:59:59"),
+            (1_709_251_199, 3600, "2024/03/01 00:59:59"),
+            (951_782_400, -1800, "2000/02/28 23:30:00"),
+        ] {
+            assert_eq!(
+                Timestamp(UNIX_EPOCH + Duration::from_secs(*secs), *offset).to_string(),
+                *ts
+            );
+        }
+    }
+}
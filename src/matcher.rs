@@ -0,0 +1,699 @@
+use std::convert::TryFrom;
+
+use crate::{
+    db::{Database, Direction, HttpMatch, MatchQuality, MtuMatch, TcpMatch, UserAgentOs},
+    http::{Header as HttpHeader, Signature as HttpSignature, Version as HttpVersion},
+    tcp::{IpVersion, PayloadSize, Quirk, Signature as TcpSignature, WindowSize, TTL},
+    Type,
+};
+
+/// Maximum distance between the initial TTL and the observed one.
+pub const MAX_DIST: u8 = 35;
+
+/// Minimum size of IPv4 and TCP headers.
+const MIN_TCP4: u16 = 40;
+/// Minimum size of IPv6 and TCP headers.
+const MIN_TCP6: u16 = 60;
+
+impl Database {
+    /// Find the best TCP signature that matches the observed one.
+    ///
+    /// Specified signatures are preferred over generic ones, and fuzzy matches
+    /// are only used as a last resort, and never for userland tools.
+    pub fn match_tcp(&self, sig: &TcpSignature, direction: Direction) -> Option<TcpMatch<'_>> {
+        let sigs = match direction {
+            Direction::Request => &self.tcp_request,
+            Direction::Response => &self.tcp_response,
+        };
+
+        let mut generic = None;
+        let mut fuzzy = None;
+
+        for (label, sigs) in sigs {
+            for s in sigs {
+                match s.matches(sig) {
+                    Some(MatchQuality::Exact) if label.ty == Type::Specified => {
+                        return Some(TcpMatch {
+                            label,
+                            sig: s,
+                            quality: MatchQuality::Exact,
+                            distance: s.distance_to(sig),
+                        });
+                    }
+                    Some(MatchQuality::Exact) if generic.is_none() => {
+                        generic = Some(TcpMatch {
+                            label,
+                            sig: s,
+                            quality: MatchQuality::Generic,
+                            distance: s.distance_to(sig),
+                        });
+                    }
+                    Some(MatchQuality::Fuzzy) if fuzzy.is_none() => {
+                        fuzzy = Some(TcpMatch {
+                            label,
+                            sig: s,
+                            quality: MatchQuality::Fuzzy,
+                            distance: s.distance_to(sig),
+                        });
+                    }
+                    _ => {}
+                }
+            }
+        }
+
+        generic.or_else(|| fuzzy.filter(|m| m.label.class.is_some()))
+    }
+
+    /// Find the link type of the MTU derived from the maximum segment size.
+    pub fn match_mtu(&self, sig: &TcpSignature) -> Option<MtuMatch<'_>> {
+        let mtu = sig.mtu()?;
+
+        self.mtu
+            .iter()
+            .find(|(_, values)| values.contains(&mtu))
+            .map(|(link, _)| MtuMatch { link, mtu })
+    }
+
+    /// Find the best HTTP signature that matches the observed one.
+    ///
+    /// The observed signature holds the headers in the order they appear in the traffic,
+    /// and the 'User-Agent' or 'Server' value as `expsw`.
+    pub fn match_http(&self, sig: &HttpSignature, direction: Direction) -> Option<HttpMatch<'_>> {
+        let sigs = match direction {
+            Direction::Request => &self.http_request,
+            Direction::Response => &self.http_response,
+        };
+
+        let mut generic = None;
+
+        for (label, sigs) in sigs {
+            for s in sigs.iter().filter(|s| s.matches(sig)) {
+                let m = HttpMatch {
+                    label,
+                    sig: s,
+                    quality: MatchQuality::Exact,
+                    dishonest: !sig.expsw.is_empty()
+                        && !s.expsw.is_empty()
+                        && !sig.expsw.contains(&s.expsw),
+                };
+
+                if label.ty == Type::Specified {
+                    return Some(m);
+                } else if generic.is_none() {
+                    generic = Some(HttpMatch {
+                        quality: MatchQuality::Generic,
+                        ..m
+                    });
+                }
+            }
+        }
+
+        generic
+    }
+
+    /// Find the OS claimed by the 'User-Agent', from the `ua_os` mapping.
+    ///
+    /// The OS name is searched in the 'User-Agent', unless a substring is given.
+    pub fn match_ua_os(&self, user_agent: &str) -> Option<&str> {
+        self.ua_os
+            .iter()
+            .find(|(name, substr)| user_agent.contains(substr.as_ref().unwrap_or(name).as_str()))
+            .map(|(name, _)| name.as_str())
+    }
+
+    /// Check whether the OS claimed by the 'User-Agent' is the one of the TCP match.
+    pub fn check_user_agent(&self, tcp: &TcpMatch<'_>, user_agent: &str) -> UserAgentOs<'_> {
+        // userland tools don't have an OS
+        if tcp.label.class.is_none() {
+            return UserAgentOs::Unknown;
+        }
+
+        match self.match_ua_os(user_agent) {
+            Some(claimed) if claimed == tcp.label.name => UserAgentOs::Consistent,
+            Some(claimed) => UserAgentOs::Dishonest { claimed },
+            None => UserAgentOs::Unknown,
+        }
+    }
+}
+
+impl TcpSignature {
+    /// Check whether the observed signature matches this database signature.
+    ///
+    /// Returns `MatchQuality::Exact` or `MatchQuality::Fuzzy` if it matches.
+    pub fn matches(&self, observed: &TcpSignature) -> Option<MatchQuality> {
+        if self.olayout != observed.olayout
+            || !self.matches_version(observed)
+            || self.olen != observed.olen
+            || (self.mss.is_some() && self.mss != observed.mss)
+            || (self.wscale.is_some() && self.wscale != observed.wscale)
+            || (self.pclass != PayloadSize::Any && self.pclass != observed.pclass)
+            || !self.matches_wsize(observed)
+        {
+            return None;
+        }
+
+        match (self.matches_quirks(observed)?, self.matches_ttl(observed)?) {
+            (MatchQuality::Exact, MatchQuality::Exact) => Some(MatchQuality::Exact),
+            _ => Some(MatchQuality::Fuzzy),
+        }
+    }
+
+    /// Distance to the observed host, based on the initial TTL of this signature.
+    fn distance_to(&self, observed: &TcpSignature) -> u8 {
+        self.ittl
+            .initial()
+            .checked_sub(observed.ittl.observed())
+            .or_else(|| observed.ittl.distance())
+            .unwrap_or_default()
+    }
+
+    fn matches_version(&self, observed: &TcpSignature) -> bool {
+        self.version == IpVersion::Any || self.version == observed.version
+    }
+
+    /// A fuzzy match is allowed if `df` or `id+` disappear, or `id-` or `ecn` appear.
+    fn matches_quirks(&self, observed: &TcpSignature) -> Option<MatchQuality> {
+        let quirks = self.effective_quirks(&observed.version);
+
+        let deleted = quirks
+            .iter()
+            .filter(|q| !observed.quirks.contains(q))
+            .collect::<Vec<_>>();
+        let added = observed
+            .quirks
+            .iter()
+            .filter(|q| !quirks.contains(q))
+            .collect::<Vec<_>>();
+
+        if deleted.is_empty() && added.is_empty() {
+            Some(MatchQuality::Exact)
+        } else if deleted
+            .iter()
+            .all(|q| matches!(q, Quirk::DF | Quirk::NonZeroID))
+            && added
+                .iter()
+                .all(|q| matches!(q, Quirk::ZeroID | Quirk::ECN))
+        {
+            Some(MatchQuality::Fuzzy)
+        } else {
+            None
+        }
+    }
+
+    /// Quirks of the database signature, without the ones that can't apply to the IP version.
+    fn effective_quirks(&self, version: &IpVersion) -> Vec<&Quirk> {
+        self.quirks
+            .iter()
+            .filter(|q| match (&self.version, version) {
+                (IpVersion::Any, IpVersion::V4) => **q != Quirk::FlowID,
+                (IpVersion::Any, IpVersion::V6) => {
+                    !matches!(q, Quirk::DF | Quirk::NonZeroID | Quirk::ZeroID)
+                }
+                _ => true,
+            })
+            .collect()
+    }
+
+    /// A fuzzy match is allowed if the TTL is out of range, unless marked as bad.
+    fn matches_ttl(&self, observed: &TcpSignature) -> Option<MatchQuality> {
+        let ttl = observed.ittl.observed();
+
+        match self.ittl {
+            TTL::Bad(ittl) if ttl <= ittl => Some(MatchQuality::Exact),
+            TTL::Bad(_) => None,
+            ref ittl => {
+                let ittl = ittl.initial();
+
+                if ttl <= ittl && ittl - ttl <= MAX_DIST {
+                    Some(MatchQuality::Exact)
+                } else {
+                    Some(MatchQuality::Fuzzy)
+                }
+            }
+        }
+    }
+
+    fn matches_wsize(&self, observed: &TcpSignature) -> bool {
+        let wsize = match observed.window_size() {
+            Some(wsize) => wsize,
+            None => return self.wsize == WindowSize::Any || self.wsize == observed.wsize,
+        };
+
+        match self.wsize {
+            WindowSize::Any => true,
+            WindowSize::Value(n) => wsize == n,
+            WindowSize::Mod(n) => wsize.checked_rem(n) == Some(0),
+            WindowSize::MSS(n) => match observed.mss {
+                Some(mss) => is_multiple_of(wsize, mss, n),
+                None => false,
+            },
+            WindowSize::MTU(n) => match observed.mtu() {
+                Some(mtu) => is_multiple_of(wsize, mtu, n),
+                None => false,
+            },
+        }
+    }
+
+    /// The actual window size, if it can be derived from the signature.
+    pub fn window_size(&self) -> Option<u16> {
+        let (unit, n) = match self.wsize {
+            WindowSize::Value(wsize) => return Some(wsize),
+            WindowSize::MSS(n) => (self.mss?, n),
+            WindowSize::MTU(n) => (self.mtu()?, n),
+            WindowSize::Mod(_) | WindowSize::Any => return None,
+        };
+
+        unit.checked_mul(u16::from(n))
+    }
+
+    /// Express the window size as a multiple of the MSS or MTU when possible,
+    /// the way p0f writes new signatures.
+    pub fn normalize(&mut self) {
+        let wsize = match self.wsize {
+            WindowSize::Value(wsize) if wsize != 0 => wsize,
+            _ => return,
+        };
+
+        let multiple_of = |unit: Option<u16>| {
+            unit.filter(|&unit| unit != 0 && wsize % unit == 0)
+                .and_then(|unit| u8::try_from(wsize / unit).ok())
+        };
+
+        if let Some(n) = multiple_of(self.mss) {
+            self.wsize = WindowSize::MSS(n);
+        } else if let Some(n) = multiple_of(self.mtu()) {
+            self.wsize = WindowSize::MTU(n);
+        }
+    }
+
+    /// MTU derived from the maximum segment size, if specified in TCP options.
+    pub fn mtu(&self) -> Option<u16> {
+        let hdr_len = match self.version {
+            IpVersion::V6 => MIN_TCP6,
+            _ => MIN_TCP4,
+        };
+
+        self.mss.and_then(|mss| mss.checked_add(hdr_len))
+    }
+}
+
+impl HttpSignature {
+    /// Check whether the observed signature matches this database signature.
+    pub fn matches(&self, observed: &HttpSignature) -> bool {
+        (self.version == HttpVersion::Any || self.version == observed.version)
+            && self.matches_horder(observed)
+            && self.matches_habsent(observed)
+    }
+
+    /// Headers must appear in the same order, but other headers may be interleaved.
+    fn matches_horder(&self, observed: &HttpSignature) -> bool {
+        let mut headers = observed.horder.iter();
+
+        for h in &self.horder {
+            let mut remaining = headers.clone();
+
+            match remaining.find(|o| h.is_same(o)) {
+                Some(o) => {
+                    if !h.matches_value(o) {
+                        return false;
+                    }
+
+                    headers = remaining;
+                }
+                // an optional header must not appear anywhere else
+                None if h.optional && !observed.horder.iter().any(|o| h.is_same(o)) => {}
+                None => return false,
+            }
+        }
+
+        true
+    }
+
+    fn matches_habsent(&self, observed: &HttpSignature) -> bool {
+        !self
+            .habsent
+            .iter()
+            .any(|h| observed.horder.iter().any(|o| h.is_same(o)))
+    }
+}
+
+impl HttpHeader {
+    fn is_same(&self, other: &HttpHeader) -> bool {
+        self.name.eq_ignore_ascii_case(&other.name)
+    }
+
+    /// The observed value must contain the expected one, if any.
+    fn matches_value(&self, observed: &HttpHeader) -> bool {
+        match (&self.value, &observed.value) {
+            (None, _) => true,
+            (Some(expected), Some(value)) => value.contains(expected.as_str()),
+            (Some(_), None) => false,
+        }
+    }
+}
+
+/// Check whether the window size is exactly `n` times the (non-zero) unit.
+fn is_multiple_of(wsize: u16, unit: u16, n: u8) -> bool {
+    unit != 0 && u32::from(wsize) == u32::from(unit) * u32::from(n)
+}
+
+impl TTL {
+    /// The TTL value seen on the wire.
+    pub fn observed(&self) -> u8 {
+        match *self {
+            TTL::Value(ttl) | TTL::Distance(ttl, _) | TTL::Guess(ttl) | TTL::Bad(ttl) => ttl,
+        }
+    }
+
+    /// The initial TTL used by the OS.
+    pub fn initial(&self) -> u8 {
+        match *self {
+            TTL::Distance(ttl, distance) => ttl.saturating_add(distance),
+            TTL::Guess(ttl) => initial_ttl(ttl),
+            TTL::Value(ttl) | TTL::Bad(ttl) => ttl,
+        }
+    }
+
+    /// The distance to the host, if known.
+    pub fn distance(&self) -> Option<u8> {
+        match *self {
+            TTL::Distance(_, distance) => Some(distance),
+            TTL::Value(_) | TTL::Guess(_) | TTL::Bad(_) => None,
+        }
+    }
+}
+
+/// Round the observed TTL up to the closest initial TTL commonly used by operating systems.
+pub(crate) fn initial_ttl(ttl: u8) -> u8 {
+    match ttl {
+        0..=32 => 32,
+        33..=64 => 64,
+        65..=128 => 128,
+        _ => 255,
+    }
+}
+
+#[cfg(all(test, feature = "parse"))]
+mod tests {
+    use super::*;
+
+    const DATABASE: &str = r#"
+[mtu]
+
+label = Ethernet or modem
+sig   = 576
+sig   = 1500
+
+label = DSL
+sig   = 1452
+sig   = 1454
+sig   = 1492
+
+label = generic tunnel or VPN
+sig   = 1300
+sig   = 1400
+sig   = 1420
+sig   = 1440
+sig   = 1450
+sig   = 1460
+
+[tcp:request]
+
+label = g:unix:Linux:
+sig   = *:64:0:*:*,*:mss,sok,ts,nop,ws:df,id+:0
+
+label = s:unix:Linux:3.11 and newer
+sig   = *:64:0:*:mss*20,10:mss,sok,ts,nop,ws:df,id+:0
+
+label = s:win:Windows:7 or 8
+sig   = *:128:0:*:8192,8:mss,nop,ws,nop,nop,sok:df,id+:0
+
+label = s:unix:FreeBSD:9.x or newer
+sig   = *:64:0:*:65535,6:mss,nop,ws,sok,ts:df,id+:0
+
+label = s:!:NMap:SYN scan
+sig   = *:64-:0:1460:1024,0:mss::0
+
+[tcp:response]
+
+label = s:unix:Linux:3.x
+sig   = *:64:0:*:mss*10,0:mss:df:0
+
+[http:request]
+
+ua_os = Linux,Windows,iOS=[iPhone],iOS=[iPad],Mac OS X,FreeBSD
+
+label = s:!:Firefox:2.x
+sig   = *:Host,User-Agent,Accept=[,*/*;q=],?Accept-Language,Accept-Encoding=[gzip,deflate],Accept-Charset=[utf-8;q=0.7,*;q=0.7],Keep-Alive=[300],Connection=[keep-alive]::Firefox/
+
+label = s:!:wget:
+sig   = *:User-Agent,Accept=[*/*],Host,Connection=[Keep-Alive]:Accept-Encoding,Accept-Language,Accept-Charset,Cookie:Wget/
+
+label = g:!:HTTP/1.1 client:
+sig   = 1:Host::
+"#;
+
+    #[test]
+    fn test_match_tcp() {
+        let db: Database = DATABASE.parse().unwrap();
+
+        for (sig, direction, matched) in &[
+            (
+                "4:57:0:1460:29200,10:mss,sok,ts,nop,ws:df,id+:0",
+                Direction::Request,
+                Some(("Linux", MatchQuality::Exact, 7)),
+            ),
+            (
+                "6:64:0:1440:28800,10:mss,sok,ts,nop,ws::0",
+                Direction::Request,
+                Some(("Linux", MatchQuality::Exact, 0)),
+            ),
+            (
+                "4:120:0:1460:8192,8:mss,nop,ws,nop,nop,sok:df,id+:0",
+                Direction::Request,
+                Some(("Windows", MatchQuality::Exact, 8)),
+            ),
+            (
+                "4:40:0:1460:1024,0:mss::0",
+                Direction::Request,
+                Some(("NMap", MatchQuality::Exact, 24)),
+            ),
+            (
+                "4:64:0:1460:29200,7:mss,sok,ts,nop,ws:df,id+:0",
+                Direction::Request,
+                Some(("Linux", MatchQuality::Generic, 0)),
+            ),
+            (
+                "4:20:0:1460:65535,6:mss,nop,ws,sok,ts:df,id+:0",
+                Direction::Request,
+                Some(("FreeBSD", MatchQuality::Fuzzy, 44)),
+            ),
+            (
+                "4:64:0:1460:8192,8:mss,nop,ws,nop,nop,sok:id-:0",
+                Direction::Request,
+                Some(("Windows", MatchQuality::Fuzzy, 64)),
+            ),
+            (
+                "4:64:0:1460:8192,8:mss,nop,ws,nop,nop,sok:df,id+,ack+:0",
+                Direction::Request,
+                None,
+            ),
+            // too far from the initial TTL to tell the distance
+            (
+                "4:65+?:0:1460:29200,10:mss,sok,ts,nop,ws:df,id+:0",
+                Direction::Request,
+                Some(("Linux", MatchQuality::Fuzzy, 0)),
+            ),
+            ("4:250:0:1460:1024,0:mss::0", Direction::Request, None),
+            ("4:20:0:1460:1024,0:mss:df:0", Direction::Request, None),
+            (
+                "4:64:0:1460:14600,0:mss:df:0",
+                Direction::Response,
+                Some(("Linux", MatchQuality::Exact, 0)),
+            ),
+            ("4:64:0:1460:14600,0:mss:df:0", Direction::Request, None),
+        ] {
+            let sig: TcpSignature = sig.parse().unwrap();
+
+            assert_eq!(
+                db.match_tcp(&sig, *direction).map(|m| (
+                    m.label.name.as_str(),
+                    m.quality,
+                    m.distance
+                )),
+                *matched,
+                "{}",
+                sig
+            );
+        }
+    }
+
+    #[test]
+    fn test_ttl() {
+        for (ttl, observed, initial, distance) in &[
+            (TTL::Value(64), 64, 64, None),
+            (TTL::Distance(57, 7), 57, 64, Some(7)),
+            (TTL::Guess(65), 65, 128, None),
+            (TTL::Guess(200), 200, 255, None),
+            (TTL::Bad(64), 64, 64, None),
+        ] {
+            assert_eq!(ttl.observed(), *observed, "{:?}", ttl);
+            assert_eq!(ttl.initial(), *initial, "{:?}", ttl);
+            assert_eq!(ttl.distance(), *distance, "{:?}", ttl);
+        }
+    }
+
+    #[test]
+    fn test_normalize() {
+        for (sig, normalized) in &[
+            (
+                "4:64+0:0:1460:29200,7:mss,sok,ts,nop,ws:df,id+:0",
+                "4:64+0:0:1460:mss*20,7:mss,sok,ts,nop,ws:df,id+:0",
+            ),
+            (
+                "6:64+0:0:1440:28800,7:mss,sok,ts,nop,ws::0",
+                "6:64+0:0:1440:mss*20,7:mss,sok,ts,nop,ws::0",
+            ),
+            (
+                "4:128+0:0:1460:3000,0:mss,nop,ws::0",
+                "4:128+0:0:1460:mtu*2,0:mss,nop,ws::0",
+            ),
+            (
+                "6:128+0:0:1440:4500,0:mss,nop,ws::0",
+                "6:128+0:0:1440:mtu*3,0:mss,nop,ws::0",
+            ),
+            (
+                "4:128+0:0:1460:8192,8:mss,nop,ws::0",
+                "4:128+0:0:1460:8192,8:mss,nop,ws::0",
+            ),
+            ("4:64+0:0:1:1024,0:mss::0", "4:64+0:0:1:1024,0:mss::0"),
+            ("4:64+0:0:*:0,0:nop::0", "4:64+0:0:*:0,0:nop::0"),
+        ] {
+            let mut sig: TcpSignature = sig.parse().unwrap();
+            let wsize = sig.window_size();
+
+            sig.normalize();
+
+            assert_eq!(sig.to_string(), *normalized);
+            assert_eq!(sig.window_size(), wsize);
+        }
+    }
+
+    #[test]
+    fn test_match_mtu() {
+        let db: Database = DATABASE.parse().unwrap();
+
+        for (sig, matched) in &[
+            (
+                "4:64:0:1460:mss*20,7:mss,sok,ts,nop,ws:df,id+:0",
+                Some(("Ethernet or modem", 1500)),
+            ),
+            (
+                "6:64:0:1440:mss*20,7:mss,sok,ts,nop,ws::0",
+                Some(("Ethernet or modem", 1500)),
+            ),
+            (
+                "4:64:0:1452:mss*20,7:mss,sok,ts,nop,ws:df,id+:0",
+                Some(("DSL", 1492)),
+            ),
+            (
+                "4:64:0:1360:mss*20,7:mss,sok,ts,nop,ws:df,id+:0",
+                Some(("generic tunnel or VPN", 1400)),
+            ),
+            ("4:64:0:1000:mss*20,7:mss,sok,ts,nop,ws:df,id+:0", None),
+            ("4:64:0:*:8192,0:nop::0", None),
+        ] {
+            let sig: TcpSignature = sig.parse().unwrap();
+
+            assert_eq!(
+                db.match_mtu(&sig).map(|m| (m.link, m.mtu)),
+                *matched,
+                "{}",
+                sig
+            );
+        }
+    }
+
+    #[test]
+    fn test_match_http() {
+        let db: Database = DATABASE.parse().unwrap();
+
+        for (sig, matched) in &[
+            (
+                "1:Host,User-Agent,Accept=[text/html,*/*;q=0.8],Accept-Language=[en-us],Accept-Encoding=[gzip,deflate],Accept-Charset=[utf-8;q=0.7,*;q=0.7],Keep-Alive=[300],Connection=[keep-alive]::Mozilla/5.0 Firefox/2.0",
+                Some(("Firefox", MatchQuality::Exact, false)),
+            ),
+            (
+                "0:Host,User-Agent,Accept=[text/html,*/*;q=0.8],Accept-Encoding=[gzip,deflate],Accept-Charset=[utf-8;q=0.7,*;q=0.7],Keep-Alive=[300],Connection=[keep-alive]::Mozilla/5.0 Firefox/2.0",
+                Some(("Firefox", MatchQuality::Exact, false)),
+            ),
+            (
+                "1:Host,User-Agent,Accept=[text/html,*/*;q=0.8],Accept-Encoding=[gzip,deflate],Accept-Charset=[utf-8;q=0.7,*;q=0.7],Keep-Alive=[300],Connection=[keep-alive]::curl/7.64.0",
+                Some(("Firefox", MatchQuality::Exact, true)),
+            ),
+            (
+                "1:Host,User-Agent,Accept=[text/html,*/*;q=0.8],Accept-Encoding=[gzip,deflate],Accept-Charset=[utf-8;q=0.7,*;q=0.7],Keep-Alive=[300],Connection=[keep-alive],Accept-Language=[en-us]::Mozilla/5.0 Firefox/2.0",
+                Some(("HTTP/1.1 client", MatchQuality::Generic, false)),
+            ),
+            (
+                "0:User-Agent,Accept=[*/*],Host,Connection=[Keep-Alive]::Wget/1.12",
+                Some(("wget", MatchQuality::Exact, false)),
+            ),
+            (
+                "0:User-Agent,Accept=[*/*],Host,Connection=[Keep-Alive],Cookie::Wget/1.12",
+                None,
+            ),
+        ] {
+            let sig: HttpSignature = sig.parse().unwrap();
+
+            assert_eq!(
+                db.match_http(&sig, Direction::Request)
+                    .map(|m| (m.label.name.as_str(), m.quality, m.dishonest)),
+                *matched,
+                "{}",
+                sig
+            );
+        }
+    }
+
+    #[test]
+    fn test_check_user_agent() {
+        let db: Database = DATABASE.parse().unwrap();
+        let linux: TcpSignature = "4:64+0:0:1460:mss*20,10:mss,sok,ts,nop,ws:df,id+:0"
+            .parse()
+            .unwrap();
+        let nmap: TcpSignature = "4:54+10:0:1460:1024,0:mss::0".parse().unwrap();
+
+        assert_eq!(
+            db.match_ua_os("Mozilla/5.0 (iPhone; CPU iPhone OS 12_0 like Mac OS X)"),
+            Some("iOS")
+        );
+
+        let linux = db.match_tcp(&linux, Direction::Request).unwrap();
+
+        for (user_agent, consistency) in &[
+            (
+                "Mozilla/5.0 (X11; Linux x86_64; rv:68.0) Gecko/20100101 Firefox/68.0",
+                UserAgentOs::Consistent,
+            ),
+            (
+                "Mozilla/5.0 (Windows NT 10.0; Win64; x64) AppleWebKit/537.36",
+                UserAgentOs::Dishonest { claimed: "Windows" },
+            ),
+            (
+                "Mozilla/5.0 (iPad; CPU OS 12_0 like Mac OS X)",
+                UserAgentOs::Dishonest { claimed: "iOS" },
+            ),
+            ("curl/7.64.0", UserAgentOs::Unknown),
+        ] {
+            assert_eq!(db.check_user_agent(&linux, user_agent), *consistency);
+        }
+
+        let nmap = db.match_tcp(&nmap, Direction::Request).unwrap();
+
+        assert_eq!(
+            db.check_user_agent(&nmap, "Mozilla/5.0 (Windows NT 10.0)"),
+            UserAgentOs::Unknown
+        );
+    }
+}
@@ -0,0 +1,112 @@
+use crate::{host::Host, tcp, Label};
+
+/// Score from which the address is considered shared by several hosts.
+pub const NAT_THRESHOLD: u32 = 4;
+
+/// Inconsistency between the traffic seen from the same address.
+#[derive(Clone, Copy, Debug, PartialEq)]
+pub enum NatReason {
+    /// the OS changed.
+    Os,
+    /// the TCP signature changed, while the OS didn't or is unknown.
+    Signature,
+    /// the distance changed by more than one hop.
+    Distance,
+    /// the TCP timestamp doesn't follow the previous one.
+    Timestamp,
+    /// the link type changed.
+    Link,
+    /// the HTTP client or server software changed.
+    Http,
+}
+
+impl NatReason {
+    pub fn score(self) -> u32 {
+        match self {
+            NatReason::Os | NatReason::Timestamp => 4,
+            NatReason::Signature | NatReason::Http => 2,
+            NatReason::Distance | NatReason::Link => 1,
+        }
+    }
+}
+
+/// Evidence that several hosts share the same address, e.g. behind a NAT or a load balancer.
+#[derive(Clone, Debug, Default, PartialEq)]
+pub struct Nat {
+    /// sum of the scores of all the inconsistencies seen.
+    pub score: u32,
+    /// the distinct reasons, in the order they were first seen.
+    pub reasons: Vec<NatReason>,
+    /// number of times each of the reasons was seen.
+    pub hits: Vec<u32>,
+}
+
+impl Nat {
+    pub fn is_shared(&self) -> bool {
+        self.score >= NAT_THRESHOLD
+    }
+
+    /// Number of times the reason was seen.
+    pub fn hits(&self, reason: NatReason) -> u32 {
+        self.reasons
+            .iter()
+            .position(|&r| r == reason)
+            .map(|pos| self.hits[pos])
+            .unwrap_or_default()
+    }
+
+    pub(crate) fn add(&mut self, reason: NatReason) {
+        self.score += reason.score();
+
+        match self.reasons.iter().position(|&r| r == reason) {
+            Some(pos) => self.hits[pos] += 1,
+            None => {
+                self.reasons.push(reason);
+                self.hits.push(1);
+            }
+        }
+    }
+}
+
+/// Compare a TCP handshake with the previous one of the same host.
+pub(crate) fn check_tcp(
+    host: &Host,
+    sig: &tcp::Signature,
+    label: Option<&Label>,
+    distance: Option<u8>,
+) -> Vec<NatReason> {
+    let mut reasons = vec![];
+
+    match (host.os.as_ref().map(|os| &os.label), label) {
+        (Some(last), Some(label)) if !same_os(last, label) => reasons.push(NatReason::Os),
+        _ => {
+            if let Some(ref last) = host.sig {
+                if !same_stack(last, sig) {
+                    reasons.push(NatReason::Signature);
+                }
+            }
+        }
+    }
+
+    if let (Some(last), Some(distance)) = (host.distance, distance) {
+        if last.max(distance) - last.min(distance) > 1 {
+            reasons.push(NatReason::Distance);
+        }
+    }
+
+    reasons
+}
+
+/// Same OS, regardless of the version.
+pub(crate) fn same_os(a: &Label, b: &Label) -> bool {
+    a.class == b.class && a.name == b.name
+}
+
+/// Same TCP/IP stack, regardless of the link and the distance.
+fn same_stack(a: &tcp::Signature, b: &tcp::Signature) -> bool {
+    a.version == b.version
+        && a.ittl.initial() == b.ittl.initial()
+        && a.olayout == b.olayout
+        && a.quirks == b.quirks
+        && a.wscale == b.wscale
+}
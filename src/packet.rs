@@ -1,47 +1,208 @@
-use std::convert::TryInto;
+use std::fmt;
+use std::net::IpAddr;
 
-use failure::{bail, err_msg, Error};
+use failure::{bail, err_msg, Error, Fail};
 
 use pnet::packet::{
-    ethernet::{EtherType, EtherTypes, EthernetPacket},
-    ip::IpNextHeaderProtocols,
+    ethernet::{EtherType, EtherTypes},
+    ip::{IpNextHeaderProtocol, IpNextHeaderProtocols},
     ipv4::{Ipv4Flags, Ipv4Packet},
     ipv6::Ipv6Packet,
-    tcp::{TcpFlags, TcpOptionNumbers::*, TcpOptionPacket, TcpPacket},
+    tcp::{TcpFlags, TcpOptionNumber, TcpOptionNumbers::*, TcpPacket},
     vlan::VlanPacket,
-    Packet, PacketSize,
+    Packet,
 };
 
+use crate::db::{Database, Direction, TcpMatch};
+use crate::matcher::{initial_ttl, MAX_DIST};
 use crate::tcp::{IpVersion, PayloadSize, Quirk, Signature, TcpOption, WindowSize, TTL};
 
+/// Return an `Unsupported` error, formatted like `bail!`.
+macro_rules! unsupported {
+    ($($arg:tt)*) => {
+        return Err($crate::packet::Unsupported(format!($($arg)*)).into())
+    };
+}
+
+mod fragment;
+mod http;
+mod link;
+mod tunnel;
+
+use self::fragment::Fragment;
+
+pub use self::fragment::Reassembler;
+pub use self::http::HttpObservation;
+pub use self::link::Datalink;
+pub use self::tunnel::{Tunnel, TunnelKind};
+
+/// Well-formed packet without a TCP handshake to extract the signature from, e.g. ARP or UDP.
+#[derive(Debug)]
+pub struct Unsupported(String);
+
+impl fmt::Display for Unsupported {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        f.write_str(&self.0)
+    }
+}
+
+impl Fail for Unsupported {}
+
+/// Encapsulation stripped before reaching the IP packet.
+#[derive(Clone, Debug, Default, PartialEq)]
+pub struct Encapsulation {
+    /// VLAN IDs, from the outermost 802.1Q or 802.1ad tag.
+    pub vlan_ids: Vec<u16>,
+    /// MPLS labels, from the top of the label stack.
+    pub mpls_labels: Vec<u32>,
+    /// PPPoE session ID.
+    pub pppoe_session: Option<u16>,
+    /// Tunnels, from the outermost one.
+    pub tunnels: Vec<Tunnel>,
+}
+
+/// Maximum number of nested VLAN tags, MPLS labels and tunnels.
+const MAX_ENCAPSULATION_DEPTH: usize = 16;
+
+impl Encapsulation {
+    /// Number of nested VLAN tags, MPLS labels and tunnels.
+    pub fn depth(&self) -> usize {
+        self.vlan_ids.len() + self.mpls_labels.len() + self.tunnels.len()
+    }
+}
+
+/// Fields of the observed packet that are not part of the signature.
+#[derive(Clone, Debug, PartialEq)]
+pub struct Observation {
+    /// source address of the innermost IP header.
+    pub src: IpAddr,
+    /// destination address of the innermost IP header.
+    pub dst: IpAddr,
+    pub src_port: u16,
+    pub dst_port: u16,
+    /// TCP flags, see `pnet::packet::tcp::TcpFlags`.
+    pub flags: u16,
+    pub seq: u32,
+    pub ack: u32,
+    /// TTL or hop limit, as seen on the wire.
+    pub ttl: u8,
+    /// window size, without scaling.
+    pub window: u16,
+    /// maximum segment size option.
+    pub mss: Option<u16>,
+    /// window scaling option, zero if absent as p0f reports it.
+    pub wscale: u8,
+    /// timestamp option, the sender's timestamp value.
+    pub tsval: Option<u32>,
+    /// timestamp option, the echoed timestamp.
+    pub tsecr: Option<u32>,
+    /// TCP payload.
+    pub payload: Vec<u8>,
+    /// encapsulation stripped before reaching the IP packet.
+    pub encap: Encapsulation,
+}
+
+/// Role of the TCP segment in the handshake.
+#[derive(Clone, Copy, Debug, PartialEq)]
+pub enum Segment {
+    /// SYN from the client.
+    Syn,
+    /// SYN+ACK from the server.
+    SynAck,
+    /// any other segment, e.g. carrying the payload.
+    Other,
+}
+
+impl Observation {
+    /// Classify the segment from its TCP flags.
+    pub fn segment(&self) -> Segment {
+        use TcpFlags::*;
+
+        let tcp_type = self.flags & (SYN | ACK | FIN | RST);
+
+        if tcp_type == SYN {
+            Segment::Syn
+        } else if tcp_type == SYN | ACK {
+            Segment::SynAck
+        } else {
+            Segment::Other
+        }
+    }
+
+    /// The database section to look up, or `None` if not a handshake segment.
+    pub fn direction(&self) -> Option<Direction> {
+        match self.segment() {
+            Segment::Syn => Some(Direction::Request),
+            Segment::SynAck => Some(Direction::Response),
+            Segment::Other => None,
+        }
+    }
+
+    fn ensure_handshake(&self) -> Result<(), Error> {
+        if self.segment() == Segment::Other {
+            unsupported!(
+                "unsupport TCP segment, neither SYN nor SYN+ACK: {}",
+                self.flags
+            );
+        }
+
+        Ok(())
+    }
+}
+
+impl Database {
+    /// Find the best TCP signature for the observed handshake segment,
+    /// in the `tcp:request` section for SYN and `tcp:response` for SYN+ACK.
+    pub fn match_observed(&self, sig: &Signature, obs: &Observation) -> Option<TcpMatch<'_>> {
+        obs.direction()
+            .and_then(|direction| self.match_tcp(sig, direction))
+    }
+}
+
 impl Signature {
     pub fn extract(packet: &[u8]) -> Result<Self, Error> {
-        EthernetPacket::new(packet)
-            .ok_or_else(|| err_msg("ethernet packet too short"))
-            .and_then(|packet| visit_ethernet(packet.get_ethertype(), packet.payload()))
+        Signature::extract_from(Datalink::Ethernet, packet)
     }
 }
 
-fn visit_ethernet(ethertype: EtherType, payload: &[u8]) -> Result<Signature, Error> {
+fn visit_ethernet(
+    ethertype: EtherType,
+    payload: &[u8],
+    encap: &mut Encapsulation,
+) -> Result<(Signature, Observation), Error> {
+    // every nested layer is recorded before coming back here
+    if encap.depth() > MAX_ENCAPSULATION_DEPTH {
+        bail!("too many nested encapsulations: {}", encap.depth());
+    }
+
     match ethertype {
-        EtherTypes::Vlan => VlanPacket::new(payload)
+        EtherTypes::Vlan | EtherTypes::PBridge | EtherTypes::QinQ => VlanPacket::new(payload)
             .ok_or_else(|| err_msg("vlan packet too short"))
-            .and_then(visit_vlan),
+            .and_then(|packet| visit_vlan(packet, encap)),
+
+        EtherTypes::Mpls | EtherTypes::MplsMcast => link::visit_mpls(payload, encap),
+
+        EtherTypes::PppoeSession => link::visit_pppoe(payload, encap),
 
         EtherTypes::Ipv4 => Ipv4Packet::new(payload)
             .ok_or_else(|| err_msg("ipv4 packet too short"))
-            .and_then(visit_ipv4),
+            .and_then(|packet| visit_ipv4(packet, encap)),
 
         EtherTypes::Ipv6 => Ipv6Packet::new(payload)
             .ok_or_else(|| err_msg("ipv6 packet too short"))
-            .and_then(visit_ipv6),
+            .and_then(|packet| visit_ipv6(packet, encap)),
 
-        ty => bail!("unsupport ethernet type: {}", ty),
+        ty => unsupported!("unsupport ethernet type: {}", ty),
     }
 }
 
-fn visit_vlan(packet: VlanPacket) -> Result<Signature, Error> {
-    visit_ethernet(packet.get_ethertype(), packet.payload())
+fn visit_vlan(
+    packet: VlanPacket,
+    encap: &mut Encapsulation,
+) -> Result<(Signature, Observation), Error> {
+    encap.vlan_ids.push(packet.get_vlan_identifier());
+
+    visit_ethernet(packet.get_ethertype(), packet.payload(), encap)
 }
 
 /// Congestion encountered
@@ -51,22 +212,37 @@ const IP_TOS_ECT: u8 = 0x02;
 /// Must be zero
 const IP4_MBZ: u8 = 0b0100;
 
-fn visit_ipv4(packet: Ipv4Packet) -> Result<Signature, Error> {
-    if packet.get_next_level_protocol() != IpNextHeaderProtocols::Tcp {
-        bail!(
-            "unsuppport IPv4 packet with non-TCP payload: {}",
-            packet.get_next_level_protocol()
-        );
-    }
+fn visit_ipv4(
+    packet: Ipv4Packet,
+    encap: &mut Encapsulation,
+) -> Result<(Signature, Observation), Error> {
+    let payload = ipv4_payload(&packet)?;
 
     if packet.get_fragment_offset() > 0
         || (packet.get_flags() & Ipv4Flags::MoreFragments) == Ipv4Flags::MoreFragments
     {
-        bail!("unsupport IPv4 fragment");
+        let protocol = packet.get_next_level_protocol();
+
+        // only buffer what could be reassembled into a TCP segment
+        if protocol != IpNextHeaderProtocols::Tcp && !tunnel::is_tunnel(protocol) {
+            unsupported!("unsupported IPv4 fragment of non-TCP payload: {}", protocol);
+        }
+
+        return Err(Fragment::ipv4(&packet, payload, encap).into());
+    }
+
+    if packet.get_next_level_protocol() != IpNextHeaderProtocols::Tcp {
+        return tunnel::visit_tunnel(
+            packet.get_next_level_protocol(),
+            packet.get_source().into(),
+            packet.get_destination().into(),
+            payload,
+            encap,
+        );
     }
 
     let version = IpVersion::V4;
-    let ttl = TTL::Value(packet.get_ttl());
+    let ttl = guess_ttl(packet.get_ttl());
     let olen = packet.get_options_raw().len() as u8;
     let mut quirks = vec![];
 
@@ -90,22 +266,73 @@ fn visit_ipv4(packet: Ipv4Packet) -> Result<Signature, Error> {
         }
     }
 
-    TcpPacket::new(packet.payload())
+    TcpPacket::new(payload)
         .ok_or_else(|| err_msg("TCP packet too short"))
-        .and_then(|packet| visit_tcp(packet, version, ttl, olen, quirks))
+        .and_then(|tcp| {
+            visit_tcp(
+                tcp,
+                packet.get_source().into(),
+                packet.get_destination().into(),
+                version,
+                ttl,
+                olen,
+                quirks,
+            )
+        })
 }
 
-fn visit_ipv6(packet: Ipv6Packet) -> Result<Signature, Error> {
-    if packet.get_next_header() != IpNextHeaderProtocols::Tcp {
-        bail!(
-            "unsuppport IPv6 packet with non-TCP payload: {}",
-            packet.get_next_header()
+/// Payload of the IPv4 packet, up to its total length.
+///
+/// The captured frame may carry padding after the datagram, e.g. an Ethernet frame
+/// shorter than 60 bytes.
+fn ipv4_payload<'a>(packet: &'a Ipv4Packet) -> Result<&'a [u8], Error> {
+    let hlen = usize::from(packet.get_header_length()) * 4;
+    let total_len = usize::from(packet.get_total_length());
+
+    if hlen < Ipv4Packet::minimum_packet_size() || total_len < hlen {
+        bail!("invalid IPv4 header length: {}", hlen);
+    }
+    if total_len > packet.packet().len() {
+        bail!("IPv4 total length would end past end of packet");
+    }
+
+    Ok(&packet.packet()[hlen..total_len])
+}
+
+fn visit_ipv6(
+    packet: Ipv6Packet,
+    encap: &mut Encapsulation,
+) -> Result<(Signature, Observation), Error> {
+    let (next_header, olen, payload) =
+        visit_ipv6_extensions(packet.get_next_header(), ipv6_payload(&packet)?)?;
+
+    if next_header == IpNextHeaderProtocols::Ipv6Frag {
+        let protocol = IpNextHeaderProtocol::new(payload[0]);
+
+        // only buffer what could be reassembled into a TCP segment
+        if protocol != IpNextHeaderProtocols::Tcp
+            && !is_ipv6_extension(protocol)
+            && !tunnel::is_tunnel(protocol)
+        {
+            unsupported!("unsupported IPv6 fragment of non-TCP payload: {}", protocol);
+        }
+
+        return Err(Fragment::ipv6(&packet, olen, payload, encap).into());
+    }
+
+    if next_header != IpNextHeaderProtocols::Tcp {
+        return tunnel::visit_tunnel(
+            next_header,
+            packet.get_source().into(),
+            packet.get_destination().into(),
+            payload,
+            encap,
         );
     }
 
     let version = IpVersion::V6;
-    let ttl = TTL::Value(packet.get_hop_limit());
-    let mut olen = 0; // TODO handle extensions
+    let ttl = guess_ttl(packet.get_hop_limit());
+    let olen = olen.min(usize::from(u8::MAX)) as u8;
     let mut quirks = vec![];
 
     if packet.get_flow_label() != 0 {
@@ -115,18 +342,118 @@ fn visit_ipv6(packet: Ipv6Packet) -> Result<Signature, Error> {
         quirks.push(Quirk::ECN);
     }
 
-    TcpPacket::new(packet.payload())
+    TcpPacket::new(payload)
         .ok_or_else(|| err_msg("TCP packet too short"))
-        .and_then(|packet| visit_tcp(packet, version, ttl, olen, quirks))
+        .and_then(|tcp| {
+            visit_tcp(
+                tcp,
+                packet.get_source().into(),
+                packet.get_destination().into(),
+                version,
+                ttl,
+                olen,
+                quirks,
+            )
+        })
+}
+
+/// Payload of the IPv6 packet, up to its payload length, see `ipv4_payload`.
+fn ipv6_payload<'a>(packet: &'a Ipv6Packet) -> Result<&'a [u8], Error> {
+    let hlen = Ipv6Packet::minimum_packet_size();
+    let total_len = hlen + usize::from(packet.get_payload_length());
+
+    if total_len > packet.packet().len() {
+        bail!("IPv6 payload length would end past end of packet");
+    }
+
+    Ok(&packet.packet()[hlen..total_len])
+}
+
+/// Fragment offset mask in the IPv6 fragment header
+const IP6_FRAG_OFFSET: u16 = 0xfff8;
+/// More fragments flag in the IPv6 fragment header
+const IP6_FRAG_MORE: u16 = 0x0001;
+
+/// IPv6 extension headers that could be skipped
+const IPV6_EXTENSIONS: &[IpNextHeaderProtocol] = &[
+    IpNextHeaderProtocols::Hopopt,
+    IpNextHeaderProtocols::Ipv6Route,
+    IpNextHeaderProtocols::Ipv6Frag,
+    IpNextHeaderProtocols::Ipv6Opts,
+    IpNextHeaderProtocols::Ah,
+    IpNextHeaderProtocols::MobilityHeader,
+    IpNextHeaderProtocols::Hip,
+    IpNextHeaderProtocols::Shim6,
+];
+
+/// Walk the IPv6 extension headers, returns the upper-layer protocol,
+/// the total length of extension headers and the upper-layer payload.
+///
+/// The walk stops at the fragment header of a fragmented packet.
+fn visit_ipv6_extensions(
+    mut next_header: IpNextHeaderProtocol,
+    mut payload: &[u8],
+) -> Result<(IpNextHeaderProtocol, usize, &[u8]), Error> {
+    let mut olen = 0;
+
+    while is_ipv6_extension(next_header) {
+        if payload.len() < 8 {
+            bail!("IPv6 extension header too short: {}", next_header);
+        }
+
+        let len = match next_header {
+            IpNextHeaderProtocols::Ipv6Frag => {
+                let frag = u16::from_be_bytes([payload[2], payload[3]]);
+
+                // stop at the fragment header, unless it's an atomic fragment
+                if (frag & IP6_FRAG_OFFSET) != 0 || (frag & IP6_FRAG_MORE) != 0 {
+                    break;
+                }
+
+                8
+            }
+            IpNextHeaderProtocols::Ah => (usize::from(payload[1]) + 2) * 4,
+            _ => (usize::from(payload[1]) + 1) * 8,
+        };
+
+        if payload.len() < len {
+            bail!("IPv6 extension header too short: {}", next_header);
+        }
+
+        next_header = IpNextHeaderProtocol::new(payload[0]);
+        olen += len;
+        payload = &payload[len..];
+    }
+
+    Ok((next_header, olen, payload))
+}
+
+fn is_ipv6_extension(next_header: IpNextHeaderProtocol) -> bool {
+    IPV6_EXTENSIONS.contains(&next_header)
+}
+
+/// Guess the initial TTL used by the OS, and the distance to the host.
+///
+/// Beyond `MAX_DIST`, only the observed TTL is kept.
+fn guess_ttl(ttl: u8) -> TTL {
+    let distance = initial_ttl(ttl) - ttl;
+
+    if distance > MAX_DIST {
+        TTL::Guess(ttl)
+    } else {
+        TTL::Distance(ttl, distance)
+    }
 }
 
 fn visit_tcp(
     tcp: TcpPacket,
+    src: IpAddr,
+    dst: IpAddr,
     version: IpVersion,
     ittl: TTL,
     olen: u8,
     mut quirks: Vec<Quirk>,
-) -> Result<Signature, Error> {
+) -> Result<(Signature, Observation), Error> {
     use TcpFlags::*;
 
     let flags = tcp.get_flags();
@@ -139,7 +466,14 @@ fn visit_tcp(
         bail!("invalid TCP flags: {}", flags);
     }
 
-    if (flags & (ECE | CWR | NS)) != 0 {
+    if usize::from(tcp.get_data_offset()) * 4 < TcpPacket::minimum_packet_size() {
+        bail!("TCP header too short: {}", tcp.get_data_offset());
+    }
+    if usize::from(tcp.get_data_offset()) * 4 > tcp.packet().len() {
+        bail!("TCP header would end past end of packet");
+    }
+
+    if (flags & (ECE | CWR | NS)) != 0 && !quirks.contains(&Quirk::ECN) {
         quirks.push(Quirk::ECN);
     }
     if tcp.get_sequence() == 0 {
@@ -165,99 +499,501 @@ fn visit_tcp(
         quirks.push(Quirk::PUSH);
     }
 
-    let mut buf = tcp.get_options_raw();
-    let mut mss = None;
-    let mut wscale = None;
-    let mut olayout = vec![];
+    let opts = visit_tcp_options(tcp.get_options_raw(), tcp_type == SYN);
+
+    for quirk in opts.quirks {
+        if !quirks.contains(&quirk) {
+            quirks.push(quirk);
+        }
+    }
+
+    let sig = Signature {
+        version,
+        ittl,
+        olen,
+        mss: opts.mss,
+        wsize: WindowSize::Value(tcp.get_window()),
+        // p0f reports a missing window scaling option as zero
+        wscale: Some(opts.wscale.unwrap_or(0)),
+        olayout: opts.olayout,
+        quirks,
+        pclass: if tcp.payload().is_empty() {
+            PayloadSize::Zero
+        } else {
+            PayloadSize::NonZero
+        },
+    };
+    let obs = Observation {
+        src,
+        dst,
+        src_port: tcp.get_source(),
+        dst_port: tcp.get_destination(),
+        flags,
+        seq: tcp.get_sequence(),
+        ack: tcp.get_acknowledgement(),
+        ttl: sig.ittl.observed(),
+        window: tcp.get_window(),
+        mss: opts.mss,
+        wscale: opts.wscale.unwrap_or(0),
+        tsval: opts.timestamps.map(|(tsval, _)| tsval),
+        tsecr: opts.timestamps.map(|(_, tsecr)| tsecr),
+        payload: tcp.payload().to_vec(),
+        encap: Encapsulation::default(),
+    };
+
+    Ok((sig, obs))
+}
+
+/// TCP options decoded the way p0f does.
+#[derive(Debug, Default)]
+struct TcpOptions {
+    olayout: Vec<TcpOption>,
+    mss: Option<u16>,
+    wscale: Option<u8>,
+    /// timestamp value and echo reply.
+    timestamps: Option<(u32, u32)>,
+    quirks: Vec<Quirk>,
+}
+
+impl TcpOptions {
+    fn quirk(&mut self, quirk: Quirk) {
+        if !self.quirks.contains(&quirk) {
+            self.quirks.push(quirk);
+        }
+    }
+}
 
-    while let Some(opt) = TcpOptionPacket::new(buf) {
-        buf = &buf[opt.packet_size().min(buf.len())..];
+fn visit_tcp_options(mut buf: &[u8], syn: bool) -> TcpOptions {
+    let mut opts = TcpOptions::default();
 
-        let data = opt.payload();
+    while let Some((&kind, data)) = buf.split_first() {
+        buf = data;
 
-        match opt.get_number() {
+        // size of the remaining option data, or `None` if the option is malformed
+        // and the parsing should be aborted.
+        let len = match TcpOptionNumber::new(kind) {
             EOL => {
-                olayout.push(TcpOption::EOL(buf.len() as u8));
+                opts.olayout.push(TcpOption::EOL(buf.len() as u8));
 
                 if buf.iter().any(|&b| b != 0) {
-                    quirks.push(Quirk::TrailinigNonZero);
+                    opts.quirk(Quirk::TrailinigNonZero);
                 }
+
+                Some(buf.len())
             }
             NOP => {
-                olayout.push(TcpOption::NOP);
+                opts.olayout.push(TcpOption::NOP);
+
+                Some(0)
             }
             MSS => {
-                olayout.push(TcpOption::MSS);
+                opts.olayout.push(TcpOption::MSS);
 
-                if data.len() > 2 {
-                    mss = Some(u16::from_ne_bytes(data[..2].try_into()?));
-                }
+                if buf.len() < 3 {
+                    None
+                } else {
+                    if buf[0] != 4 {
+                        opts.quirk(Quirk::OptBad);
+                    }
 
-                if data.len() != 4 {
-                    quirks.push(Quirk::OptBad);
+                    opts.mss = Some(u16::from_be_bytes([buf[1], buf[2]]));
+
+                    Some(3)
                 }
             }
             WSCALE => {
-                olayout.push(TcpOption::WS);
+                opts.olayout.push(TcpOption::WS);
 
-                wscale = Some(data[0]);
+                if buf.len() < 2 {
+                    None
+                } else {
+                    if buf[0] != 3 {
+                        opts.quirk(Quirk::OptBad);
+                    }
+                    if buf[1] > 14 {
+                        opts.quirk(Quirk::ExcessiveWindowScaling);
+                    }
 
-                if data[0] > 14 {
-                    quirks.push(Quirk::ExcessiveWindowScaling);
-                }
-                if data.len() != 3 {
-                    quirks.push(Quirk::OptBad);
+                    opts.wscale = Some(buf[1]);
+
+                    Some(2)
                 }
             }
             SACK_PERMITTED => {
-                olayout.push(TcpOption::SOK);
+                opts.olayout.push(TcpOption::SOK);
+
+                if buf.is_empty() {
+                    None
+                } else {
+                    if buf[0] != 2 {
+                        opts.quirk(Quirk::OptBad);
+                    }
 
-                if data.len() != 2 {
-                    quirks.push(Quirk::OptBad);
+                    Some(1)
                 }
             }
             SACK => {
-                olayout.push(TcpOption::SACK);
+                opts.olayout.push(TcpOption::SACK);
 
-                match data.len() {
-                    10 | 18 | 26 | 34 => {}
-                    _ => quirks.push(Quirk::OptBad),
-                }
+                buf.first()
+                    .filter(|&&len| (10..=34).contains(&len))
+                    .map(|&len| usize::from(len) - 1)
             }
             TIMESTAMPS => {
-                olayout.push(TcpOption::TS);
-
-                if u16::from_ne_bytes(data[..4].try_into()?) == 0 {
-                    quirks.push(Quirk::OwnTimestampZero);
-                }
-
-                if tcp_type == SYN && u16::from_ne_bytes(data[4..8].try_into()?) != 0 {
-                    quirks.push(Quirk::PeerTimestampNonZero);
+                opts.olayout.push(TcpOption::TS);
+
+                if buf.len() < 9 {
+                    None
+                } else {
+                    if buf[0] != 10 {
+                        opts.quirk(Quirk::OptBad);
+                    }
+                    let tsval = u32::from_be_bytes([buf[1], buf[2], buf[3], buf[4]]);
+                    let tsecr = u32::from_be_bytes([buf[5], buf[6], buf[7], buf[8]]);
+
+                    if tsval == 0 {
+                        opts.quirk(Quirk::OwnTimestampZero);
+                    }
+                    if syn && tsecr != 0 {
+                        opts.quirk(Quirk::PeerTimestampNonZero);
+                    }
+
+                    opts.timestamps = Some((tsval, tsecr));
+
+                    Some(9)
                 }
+            }
+            _ => {
+                opts.olayout.push(TcpOption::Unknown(kind));
 
-                if data.len() != 10 {
-                    quirks.push(Quirk::OptBad);
-                }
+                buf.first()
+                    .filter(|&&len| (2..=40).contains(&len))
+                    .map(|&len| usize::from(len) - 1)
             }
+        };
+
+        match len {
+            Some(len) if len <= buf.len() => buf = &buf[len..],
             _ => {
-                olayout.push(TcpOption::Unknown(opt.get_number().0));
+                opts.quirk(Quirk::OptBad);
+                break;
             }
         }
     }
 
-    Ok(Signature {
-        version,
-        ittl,
-        olen,
-        mss,
-        wsize: WindowSize::Value(tcp.get_window()),
-        wscale,
-        olayout,
-        quirks,
-        pclass: if tcp.payload().is_empty() {
-            PayloadSize::Zero
-        } else {
-            PayloadSize::NonZero
-        },
-    })
+    opts
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[cfg(feature = "parse")]
+    #[test]
+    fn test_tcp_options() {
+        for (buf, syn, olayout, mss, wscale, quirks) in &[
+            // Linux 3.x SYN
+            (
+                &b"\x02\x04\x05\xb4\x04\x02\x08\x0a\x00\x9a\x3f\x9c\x00\x00\x00\x00\x01\x03\x03\x07"[..],
+                true,
+                "mss,sok,ts,nop,ws",
+                Some(1460),
+                Some(7),
+                "",
+            ),
+            // Windows 7 SYN
+            (
+                &b"\x02\x04\x05\xb4\x01\x03\x03\x08\x01\x01\x04\x02"[..],
+                true,
+                "mss,nop,ws,nop,nop,sok",
+                Some(1460),
+                Some(8),
+                "",
+            ),
+            // Mac OS X SYN
+            (
+                &b"\x02\x04\x05\xb4\x01\x03\x03\x05\x01\x01\x08\x0a\x3a\x4b\x1c\x2d\x00\x00\x00\x00\x04\x02\x00\x00"[..],
+                true,
+                "mss,nop,ws,nop,nop,ts,sok,eol+1",
+                Some(1460),
+                Some(5),
+                "",
+            ),
+            // Linux 3.x SYN+ACK
+            (
+                &b"\x02\x04\x05\x8c\x04\x02\x08\x0a\x00\x12\xd6\x87\x00\x9a\x3f\x9c\x01\x03\x03\x07"[..],
+                false,
+                "mss,sok,ts,nop,ws",
+                Some(1420),
+                Some(7),
+                "",
+            ),
+            // NMap SYN scan
+            (&b"\x02\x04\x05\xb4"[..], true, "mss", Some(1460), None, ""),
+            // zero own timestamp and non-zero peer timestamp
+            (
+                &b"\x08\x0a\x00\x00\x00\x00\x00\x00\x00\x01"[..],
+                true,
+                "ts",
+                None,
+                None,
+                "ts1-,ts2+",
+            ),
+            // non-zero data after EOL
+            (
+                &b"\x02\x04\x05\xb4\x00\x00\x01\x00"[..],
+                true,
+                "mss,eol+3",
+                Some(1460),
+                None,
+                "opt+",
+            ),
+            // excessive window scaling
+            (&b"\x03\x03\x0f\x00"[..], true, "ws,eol+0", None, Some(15), "exws"),
+            // truncated MSS
+            (&b"\x02\x04\x05"[..], true, "mss", None, None, "bad"),
+            // wrong MSS size
+            (&b"\x02\x05\x05\xb4"[..], true, "mss", Some(1460), None, "bad"),
+            // truncated SACK
+            (&b"\x01\x05\x0a\x00\x00\x00\x01"[..], true, "nop,sack", None, None, "bad"),
+            // invalid SACK size
+            (&b"\x05\x04\x00\x00"[..], true, "sack", None, None, "bad"),
+            // unknown option
+            (&b"\xfe\x04\x12\x34\x01\x01"[..], true, "?254,nop,nop", None, None, ""),
+            // unknown option with invalid size
+            (&b"\xfe\x01\x12\x34"[..], true, "?254", None, None, "bad"),
+        ] {
+            let opts = visit_tcp_options(buf, *syn);
+
+            assert_eq!(
+                opts.olayout,
+                olayout
+                    .split(',')
+                    .map(|s| s.parse().unwrap())
+                    .collect::<Vec<TcpOption>>()
+            );
+            assert_eq!(opts.mss, *mss);
+            assert_eq!(opts.wscale, *wscale);
+            assert_eq!(
+                opts.quirks,
+                quirks
+                    .split(',')
+                    .filter(|s| !s.is_empty())
+                    .map(|s| s.parse().unwrap())
+                    .collect::<Vec<Quirk>>()
+            );
+        }
+    }
+
+    #[cfg(feature = "display")]
+    #[test]
+    fn test_extract() {
+        let packet = [
+            // ethernet
+            0x00, 0x11, 0x22, 0x33, 0x44, 0x55, 0x66, 0x77, 0x88, 0x99, 0xaa, 0xbb, 0x08, 0x00,
+            // IPv4
+            0x45, 0x00, 0x00, 0x3c, 0x1c, 0x46, 0x40, 0x00, 0x40, 0x06, 0x00, 0x00, //
+            0xc0, 0xa8, 0x01, 0x02, 0xc0, 0xa8, 0x01, 0x01, //
+            // TCP SYN
+            0xd4, 0x31, 0x00, 0x50, 0x6e, 0x3b, 0x5b, 0x2c, 0x00, 0x00, 0x00, 0x00, //
+            0xa0, 0x02, 0x72, 0x10, 0x00, 0x00, 0x00, 0x00, //
+            0x02, 0x04, 0x05, 0xb4, 0x04, 0x02, 0x08, 0x0a, 0x00, 0x9a, 0x3f, 0x9c, //
+            0x00, 0x00, 0x00, 0x00, 0x01, 0x03, 0x03, 0x07,
+        ];
+
+        assert_eq!(
+            Signature::extract(&packet).unwrap().to_string(),
+            "4:64+0:0:1460:29200,7:mss,sok,ts,nop,ws:df,id+:0"
+        );
+    }
+
+    #[test]
+    fn test_observe() {
+        let packet = [
+            // IPv4
+            0x45, 0x00, 0x00, 0x3c, 0x1c, 0x46, 0x40, 0x00, 0x3a, 0x06, 0x00, 0x00, //
+            0xc0, 0xa8, 0x01, 0x02, 0xc0, 0xa8, 0x01, 0x01, //
+            // TCP SYN
+            0xd4, 0x31, 0x00, 0x50, 0x6e, 0x3b, 0x5b, 0x2c, 0x00, 0x00, 0x00, 0x00, //
+            0xa0, 0x02, 0x72, 0x10, 0x00, 0x00, 0x00, 0x00, //
+            0x02, 0x04, 0x05, 0xb4, 0x04, 0x02, 0x08, 0x0a, 0x00, 0x9a, 0x3f, 0x9c, //
+            0x00, 0x00, 0x00, 0x00, 0x01, 0x03, 0x03, 0x07,
+        ];
+
+        let (sig, obs) = Signature::observe(Datalink::Raw, &packet).unwrap();
+
+        assert_eq!(sig.ittl, TTL::Distance(58, 6));
+        assert_eq!(
+            obs,
+            Observation {
+                src: "192.168.1.2".parse().unwrap(),
+                dst: "192.168.1.1".parse().unwrap(),
+                src_port: 54321,
+                dst_port: 80,
+                flags: TcpFlags::SYN,
+                seq: 0x6e3b_5b2c,
+                ack: 0,
+                ttl: 58,
+                window: 29200,
+                mss: Some(1460),
+                wscale: 7,
+                tsval: Some(0x009a_3f9c),
+                tsecr: Some(0),
+                payload: vec![],
+                encap: Encapsulation::default(),
+            }
+        );
+        assert_eq!(obs.segment(), Segment::Syn);
+        assert_eq!(obs.direction(), Some(Direction::Request));
+
+        // SYN+ACK
+        let mut synack = packet;
+        synack[33] = 0x12;
+
+        let (_, obs) = Signature::observe(Datalink::Raw, &synack).unwrap();
+
+        assert_eq!(obs.segment(), Segment::SynAck);
+        assert_eq!(obs.direction(), Some(Direction::Response));
+
+        // ACK with payload is only accepted when asked
+        let mut ack = packet.to_vec();
+        ack[3] = 0x40;
+        ack[33] = 0x18;
+        ack.extend_from_slice(b"GET ");
+
+        assert!(Signature::observe(Datalink::Raw, &ack).is_err());
+
+        let (sig, obs) = Signature::observe_any(Datalink::Raw, &ack).unwrap();
+
+        assert_eq!(sig.pclass, PayloadSize::NonZero);
+        assert_eq!(obs.segment(), Segment::Other);
+        assert_eq!(obs.direction(), None);
+        assert_eq!(obs.payload, b"GET ");
+    }
+
+    #[test]
+    fn test_padded_frame() {
+        let frame = [
+            // Ethernet
+            0x00, 0x11, 0x22, 0x33, 0x44, 0x55, 0x66, 0x77, 0x88, 0x99, 0xaa, 0xbb, 0x08, 0x00,
+            // IPv4
+            0x45, 0x00, 0x00, 0x28, 0x12, 0x34, 0x40, 0x00, 0x40, 0x06, 0x00, 0x00, //
+            0xc0, 0xa8, 0x01, 0x02, 0xc0, 0xa8, 0x01, 0x01, //
+            // TCP SYN, without options
+            0xd4, 0x31, 0x00, 0x50, 0x6e, 0x3b, 0x5b, 0x2c, 0x00, 0x00, 0x00, 0x00, //
+            0x50, 0x02, 0x20, 0x00, 0x00, 0x00, 0x00, 0x00, //
+            // padded to the minimum Ethernet frame size
+            0x00, 0x00, 0x00, 0x00, 0x00, 0x00,
+        ];
+
+        let (sig, obs) = Signature::observe(Datalink::Ethernet, &frame).unwrap();
+
+        assert_eq!(sig.pclass, PayloadSize::Zero);
+        assert!(obs.payload.is_empty());
+
+        let mut ipv6 = [
+            // IPv6
+            0x60, 0x00, 0x00, 0x00, 0x00, 0x14, 0x06, 0x40, //
+            0x20, 0x01, 0x0d, 0xb8, 0x00, 0x00, 0x00, 0x00, //
+            0x00, 0x00, 0x00, 0x00, 0x00, 0x00, 0x00, 0x01, //
+            0x20, 0x01, 0x0d, 0xb8, 0x00, 0x00, 0x00, 0x00, //
+            0x00, 0x00, 0x00, 0x00, 0x00, 0x00, 0x00, 0x02, //
+            // TCP SYN, without options
+            0xd4, 0x31, 0x00, 0x50, 0x6e, 0x3b, 0x5b, 0x2c, 0x00, 0x00, 0x00, 0x00, //
+            0x50, 0x02, 0x20, 0x00, 0x00, 0x00, 0x00, 0x00, //
+            // trailing bytes after the payload length
+            0x00, 0x00, 0x00, 0x00,
+        ];
+
+        let (sig, obs) = Signature::observe(Datalink::Ipv6, &ipv6).unwrap();
+
+        assert_eq!(sig.pclass, PayloadSize::Zero);
+        assert!(obs.payload.is_empty());
+
+        // the length is past the captured packet
+        ipv6[5] = 0x20;
+
+        assert!(Signature::observe(Datalink::Ipv6, &ipv6).is_err());
+        assert!(Signature::observe(Datalink::Ethernet, &frame[..50]).is_err());
+    }
+
+    #[cfg(feature = "parse")]
+    #[test]
+    fn test_missing_wscale() {
+        let packet = [
+            // IPv4
+            0x45, 0x00, 0x00, 0x2c, 0x12, 0x34, 0x00, 0x00, 0x40, 0x06, 0x00, 0x00, //
+            0xc0, 0xa8, 0x01, 0x02, 0xc0, 0xa8, 0x01, 0x01, //
+            // TCP SYN, with only the maximum segment size option
+            0xd4, 0x31, 0x00, 0x50, 0x6e, 0x3b, 0x5b, 0x2c, 0x00, 0x00, 0x00, 0x00, //
+            0x60, 0x02, 0x04, 0x00, 0x00, 0x00, 0x00, 0x00, //
+            0x02, 0x04, 0x05, 0xb4,
+        ];
+
+        let (sig, obs) = Signature::observe(Datalink::Raw, &packet).unwrap();
+
+        assert_eq!(sig.wscale, Some(0));
+        assert_eq!(obs.wscale, 0);
+
+        let db_sig: Signature = "*:64:0:*:1024,0:mss::0".parse().unwrap();
+
+        assert_eq!(db_sig.matches(&sig), Some(crate::db::MatchQuality::Exact));
+    }
+
+    #[test]
+    fn test_ipv6_extensions() {
+        let packet = [
+            // ethernet
+            0x00, 0x11, 0x22, 0x33, 0x44, 0x55, 0x66, 0x77, 0x88, 0x99, 0xaa, 0xbb, 0x86, 0xdd,
+            // IPv6, next header: hop-by-hop options
+            0x60, 0x00, 0x00, 0x00, 0x00, 0x2c, 0x00, 0x40, //
+            0x20, 0x01, 0x0d, 0xb8, 0x00, 0x00, 0x00, 0x00, //
+            0x00, 0x00, 0x00, 0x00, 0x00, 0x00, 0x00, 0x01, //
+            0x20, 0x01, 0x0d, 0xb8, 0x00, 0x00, 0x00, 0x00, //
+            0x00, 0x00, 0x00, 0x00, 0x00, 0x00, 0x00, 0x02, //
+            // hop-by-hop options, next header: destination options
+            0x3c, 0x00, 0x01, 0x04, 0x00, 0x00, 0x00, 0x00, //
+            // destination options, next header: fragment
+            0x2c, 0x00, 0x01, 0x04, 0x00, 0x00, 0x00, 0x00, //
+            // atomic fragment, next header: TCP
+            0x06, 0x00, 0x00, 0x00, 0x12, 0x34, 0x56, 0x78, //
+            // TCP SYN
+            0x30, 0x39, 0x00, 0x50, 0x00, 0x00, 0x00, 0x01, //
+            0x00, 0x00, 0x00, 0x00, 0x50, 0x02, 0xff, 0xff, //
+            0x00, 0x00, 0x00, 0x00,
+        ];
+
+        let sig = Signature::extract(&packet).unwrap();
+
+        assert_eq!(sig.version, IpVersion::V6);
+        assert_eq!(sig.ittl, TTL::Distance(64, 0));
+        assert_eq!(sig.olen, 24);
+        assert_eq!(sig.wsize, WindowSize::Value(65535));
+
+        // fragment with non-zero offset
+        let mut fragment = packet;
+        fragment[73] = 0x08;
+
+        assert!(Signature::extract(&fragment).is_err());
+
+        // truncated extension header
+        assert!(Signature::extract(&packet[..60]).is_err());
+    }
+
+    #[test]
+    fn test_guess_ttl() {
+        for (ttl, guess) in &[
+            (0, TTL::Distance(0, 32)),
+            (32, TTL::Distance(32, 0)),
+            (57, TTL::Distance(57, 7)),
+            (64, TTL::Distance(64, 0)),
+            (65, TTL::Guess(65)),
+            (113, TTL::Distance(113, 15)),
+            (200, TTL::Guess(200)),
+            (240, TTL::Distance(240, 15)),
+            (255, TTL::Distance(255, 0)),
+        ] {
+            assert_eq!(guess_ttl(*ttl), *guess);
+        }
+    }
 }
@@ -0,0 +1,782 @@
+use std::collections::{BTreeMap, HashMap};
+use std::fmt;
+use std::net::IpAddr;
+use std::time::{Duration, SystemTime};
+
+use failure::{bail, Error, Fail};
+
+use pnet::packet::{
+    ip::IpNextHeaderProtocols,
+    ipv4::{Ipv4Flags, Ipv4Packet},
+    ipv6::Ipv6Packet,
+    Packet,
+};
+
+use crate::tcp::Signature;
+
+use super::{Datalink, Encapsulation, Observation, IP6_FRAG_MORE, IP6_FRAG_OFFSET};
+
+/// Maximum size of the reassembled IP payload
+const MAX_DATAGRAM_SIZE: usize = 65535;
+
+/// Fragment of an IP datagram, raised as an error while extracting the signature.
+#[derive(Debug)]
+pub(super) struct Fragment {
+    key: FragmentKey,
+    /// offset of the payload in the original datagram.
+    offset: usize,
+    /// more fragments follow.
+    more: bool,
+    /// IP header, used to rebuild the datagram from the first fragment.
+    header: Vec<u8>,
+    payload: Vec<u8>,
+    /// encapsulation stripped before reaching the fragment.
+    encap: Encapsulation,
+}
+
+#[derive(Clone, Copy, Debug, PartialEq, Eq, Hash)]
+struct FragmentKey {
+    src: IpAddr,
+    dst: IpAddr,
+    id: u32,
+    protocol: u8,
+}
+
+impl Fragment {
+    /// `payload` is bounded by the total length of the packet.
+    pub(super) fn ipv4(packet: &Ipv4Packet, payload: &[u8], encap: &Encapsulation) -> Self {
+        let hlen = (usize::from(packet.get_header_length()) * 4).min(packet.packet().len());
+
+        Fragment {
+            key: FragmentKey {
+                src: packet.get_source().into(),
+                dst: packet.get_destination().into(),
+                id: u32::from(packet.get_identification()),
+                protocol: packet.get_next_level_protocol().0,
+            },
+            offset: usize::from(packet.get_fragment_offset()) * 8,
+            more: (packet.get_flags() & Ipv4Flags::MoreFragments) != 0,
+            header: packet.packet()[..hlen].to_vec(),
+            payload: payload.to_vec(),
+            encap: encap.clone(),
+        }
+    }
+
+    /// `frag` starts at the fragment header, after `olen` bytes of extension headers.
+    pub(super) fn ipv6(
+        packet: &Ipv6Packet,
+        olen: usize,
+        frag: &[u8],
+        encap: &Encapsulation,
+    ) -> Self {
+        let mut header = packet.packet()[..Ipv6Packet::minimum_packet_size() + olen].to_vec();
+        let frag_off = u16::from_be_bytes([frag[2], frag[3]]);
+
+        // the last unfragmentable header now points to the fragmented protocol
+        let pos = last_next_header(&header);
+        header[pos] = frag[0];
+
+        Fragment {
+            key: FragmentKey {
+                src: packet.get_source().into(),
+                dst: packet.get_destination().into(),
+                id: u32::from_be_bytes([frag[4], frag[5], frag[6], frag[7]]),
+                protocol: frag[0],
+            },
+            offset: usize::from(frag_off & IP6_FRAG_OFFSET),
+            more: (frag_off & IP6_FRAG_MORE) != 0,
+            header,
+            payload: frag[8..].to_vec(),
+            encap: encap.clone(),
+        }
+    }
+
+    fn is_ipv4(&self) -> bool {
+        self.key.src.is_ipv4()
+    }
+}
+
+/// Encapsulation of an inner packet, within the outer one.
+fn nest(mut outer: Encapsulation, inner: Encapsulation) -> Encapsulation {
+    outer.vlan_ids.extend(inner.vlan_ids);
+    outer.mpls_labels.extend(inner.mpls_labels);
+    outer.pppoe_session = outer.pppoe_session.or(inner.pppoe_session);
+    outer.tunnels.extend(inner.tunnels);
+    outer
+}
+
+/// Position of the next header field pointing to the fragment header.
+fn last_next_header(header: &[u8]) -> usize {
+    let mut pos = 6;
+    let mut off = Ipv6Packet::minimum_packet_size();
+
+    while off + 2 <= header.len() {
+        let len = if header[pos] == IpNextHeaderProtocols::Ah.0 {
+            (usize::from(header[off + 1]) + 2) * 4
+        } else {
+            (usize::from(header[off + 1]) + 1) * 8
+        };
+
+        pos = off;
+        off += len;
+    }
+
+    pos
+}
+
+impl fmt::Display for Fragment {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        write!(
+            f,
+            "unsupport IPv{} fragment",
+            if self.is_ipv4() { 4 } else { 6 }
+        )
+    }
+}
+
+impl Fail for Fragment {}
+
+/// Reassemble fragmented IPv4 and IPv6 datagrams before extracting the signature.
+///
+/// Overlapping fragments drop the whole datagram, and datagrams not completed
+/// within the timeout are expired.
+#[derive(Debug)]
+pub struct Reassembler {
+    timeout: Duration,
+    max_datagrams: usize,
+    max_fragments: usize,
+    max_bytes: usize,
+    any_segment: bool,
+    /// payload bytes buffered in all datagrams.
+    buffered: usize,
+    tick: u64,
+    datagrams: HashMap<FragmentKey, Datagram>,
+    /// datagrams by their creation time, the oldest first.
+    by_age: BTreeMap<(SystemTime, u64), FragmentKey>,
+}
+
+#[derive(Debug)]
+struct Datagram {
+    created: SystemTime,
+    tick: u64,
+    header: Option<(Vec<u8>, Encapsulation)>,
+    /// payload length, known once the last fragment arrived.
+    total: Option<usize>,
+    fragments: Vec<(usize, Vec<u8>)>,
+}
+
+impl Datagram {
+    fn len(&self) -> usize {
+        self.fragments
+            .iter()
+            .map(|(_, payload)| payload.len())
+            .sum()
+    }
+}
+
+impl Default for Reassembler {
+    fn default() -> Self {
+        Reassembler {
+            timeout: Duration::from_secs(30),
+            max_datagrams: 1024,
+            max_fragments: 64,
+            max_bytes: 4 * 1024 * 1024,
+            any_segment: false,
+            buffered: 0,
+            tick: 0,
+            datagrams: HashMap::new(),
+            by_age: BTreeMap::new(),
+        }
+    }
+}
+
+impl Reassembler {
+    pub fn new() -> Self {
+        Self::default()
+    }
+
+    /// Incomplete datagrams are dropped after the timeout.
+    pub fn with_timeout(mut self, timeout: Duration) -> Self {
+        self.timeout = timeout;
+        self
+    }
+
+    /// Maximum number of datagrams being reassembled at the same time.
+    pub fn with_max_datagrams(mut self, max_datagrams: usize) -> Self {
+        self.max_datagrams = max_datagrams;
+        self
+    }
+
+    /// Maximum number of fragments of a single datagram.
+    pub fn with_max_fragments(mut self, max_fragments: usize) -> Self {
+        self.max_fragments = max_fragments;
+        self
+    }
+
+    /// Maximum number of payload bytes buffered for all datagrams.
+    pub fn with_max_bytes(mut self, max_bytes: usize) -> Self {
+        self.max_bytes = max_bytes;
+        self
+    }
+
+    /// Accept any TCP segment, not only SYN and SYN+ACK, see `Signature::observe_any`.
+    pub fn with_any_segment(mut self, any_segment: bool) -> Self {
+        self.any_segment = any_segment;
+        self
+    }
+
+    /// Extract the signature from a captured packet, or buffer it if it's an IP fragment.
+    ///
+    /// Returns `None` until the last missing fragment of a datagram arrived.
+    /// The observation carries the encapsulation of the first fragment.
+    pub fn extract(
+        &mut self,
+        datalink: Datalink,
+        packet: &[u8],
+        ts: SystemTime,
+    ) -> Result<Option<(Signature, Observation)>, Error> {
+        self.expire(ts);
+
+        let res = match Signature::observe_any(datalink, packet) {
+            Ok(res) => Some(res),
+            Err(err) => self.reassemble(err.downcast::<Fragment>()?, ts)?,
+        };
+
+        if let Some((_, ref obs)) = res {
+            if !self.any_segment {
+                obs.ensure_handshake()?;
+            }
+        }
+
+        Ok(res)
+    }
+
+    /// Drop the datagrams not completed within the timeout.
+    pub fn expire(&mut self, ts: SystemTime) {
+        while let Some((&(created, _), &key)) = self.by_age.iter().next() {
+            let expired = ts
+                .duration_since(created)
+                .map(|age| age >= self.timeout)
+                .unwrap_or(false);
+
+            if !expired {
+                break;
+            }
+
+            self.remove(&key);
+        }
+    }
+
+    /// Buffer the fragment, and extract the signature once its datagram is complete.
+    ///
+    /// A reassembled datagram may carry a tunnelled fragment, which is buffered in turn.
+    fn reassemble(
+        &mut self,
+        mut fragment: Fragment,
+        ts: SystemTime,
+    ) -> Result<Option<(Signature, Observation)>, Error> {
+        loop {
+            let (datalink, packet, encap) = match self.insert(fragment, ts)? {
+                Some(datagram) => datagram,
+                None => return Ok(None),
+            };
+
+            match Signature::observe_any(datalink, &packet) {
+                Ok((sig, mut obs)) => {
+                    obs.encap = nest(encap, obs.encap);
+
+                    return Ok(Some((sig, obs)));
+                }
+                Err(err) => {
+                    fragment = err.downcast::<Fragment>()?;
+                    fragment.encap = nest(encap, fragment.encap);
+                }
+            }
+        }
+    }
+
+    /// Buffer the fragment, returns the datagram once complete.
+    fn insert(
+        &mut self,
+        fragment: Fragment,
+        ts: SystemTime,
+    ) -> Result<Option<(Datalink, Vec<u8>, Encapsulation)>, Error> {
+        let Fragment {
+            key,
+            offset,
+            more,
+            header,
+            payload,
+            encap,
+        } = fragment;
+        let end = offset + payload.len();
+
+        if end > MAX_DATAGRAM_SIZE {
+            bail!("IP fragment would end past maximum datagram size");
+        }
+        if more && payload.len() % 8 != 0 {
+            bail!("IP fragment with unaligned length: {}", payload.len());
+        }
+        if !self.datagrams.contains_key(&key) && self.datagrams.len() >= self.max_datagrams {
+            bail!("too many IP datagrams being reassembled");
+        }
+        if self.buffered + payload.len() > self.max_bytes {
+            bail!("too many IP fragment bytes buffered");
+        }
+
+        if !self.datagrams.contains_key(&key) {
+            self.tick += 1;
+            self.by_age.insert((ts, self.tick), key);
+        }
+
+        let tick = self.tick;
+        let datagram = self.datagrams.entry(key).or_insert_with(|| Datagram {
+            created: ts,
+            tick,
+            header: None,
+            total: None,
+            fragments: vec![],
+        });
+
+        if datagram
+            .fragments
+            .iter()
+            .any(|(off, data)| *off == offset && *data == payload)
+        {
+            // retransmitted fragment
+            return Ok(None);
+        }
+
+        let overlapped = datagram
+            .fragments
+            .iter()
+            .any(|(off, data)| offset < off + data.len() && *off < end);
+        let inconsistent = if more {
+            matches!(datagram.total, Some(total) if end > total)
+        } else {
+            matches!(datagram.total, Some(total) if end != total)
+                || datagram
+                    .fragments
+                    .iter()
+                    .any(|(off, data)| off + data.len() > end)
+        };
+
+        if overlapped || inconsistent || datagram.fragments.len() >= self.max_fragments {
+            self.remove(&key);
+
+            if overlapped {
+                bail!("overlapping IP fragments");
+            } else if inconsistent {
+                bail!("inconsistent IP fragment length");
+            } else {
+                bail!("too many IP fragments");
+            }
+        }
+
+        if !more {
+            datagram.total = Some(end);
+        }
+        if offset == 0 {
+            datagram.header = Some((header, encap));
+        }
+
+        self.buffered += payload.len();
+        datagram.fragments.push((offset, payload));
+
+        match datagram.total {
+            Some(total) if datagram.header.is_some() && datagram.len() == total => {}
+            _ => return Ok(None),
+        }
+
+        let mut datagram = self.remove(&key).unwrap();
+        let (mut packet, encap) = datagram.header.take().unwrap();
+
+        datagram.fragments.sort_by_key(|(off, _)| *off);
+
+        for (_, data) in datagram.fragments {
+            packet.extend_from_slice(&data);
+        }
+
+        // IPv4 total length, or IPv6 payload length
+        let len = if key.src.is_ipv4() {
+            packet.len()
+        } else {
+            packet.len() - Ipv6Packet::minimum_packet_size()
+        };
+
+        if len > MAX_DATAGRAM_SIZE {
+            bail!("reassembled IP datagram too large: {}", len);
+        }
+
+        let datalink = if key.src.is_ipv4() {
+            packet[2..4].copy_from_slice(&(len as u16).to_be_bytes());
+            // clear the more fragments flag and the fragment offset, keep don't fragment
+            packet[6] &= Ipv4Flags::DontFragment << 5;
+            packet[7] = 0;
+
+            Datalink::Ipv4
+        } else {
+            packet[4..6].copy_from_slice(&(len as u16).to_be_bytes());
+
+            Datalink::Ipv6
+        };
+
+        Ok(Some((datalink, packet, encap)))
+    }
+
+    fn remove(&mut self, key: &FragmentKey) -> Option<Datagram> {
+        let datagram = self.datagrams.remove(key)?;
+
+        self.by_age.remove(&(datagram.created, datagram.tick));
+        self.buffered -= datagram.len();
+
+        Some(datagram)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::packet::Unsupported;
+    use crate::tcp::PayloadSize;
+
+    /// Linux SYN with timestamps, split after the first 24 bytes of the TCP header
+    const IPV4_FRAGMENTS: &[&[u8]] = &[
+        &[
+            0x45, 0x00, 0x00, 0x2c, 0x1c, 0x46, 0x20, 0x00, 0x40, 0x06, 0x00, 0x00, //
+            0xc0, 0xa8, 0x01, 0x02, 0xc0, 0xa8, 0x01, 0x01, //
+            0xd4, 0x31, 0x00, 0x50, 0x6e, 0x3b, 0x5b, 0x2c, 0x00, 0x00, 0x00, 0x00, //
+            0xa0, 0x02, 0x72, 0x10, 0x00, 0x00, 0x00, 0x00, //
+            0x02, 0x04, 0x05, 0xb4,
+        ],
+        &[
+            0x45, 0x00, 0x00, 0x24, 0x1c, 0x46, 0x00, 0x03, 0x40, 0x06, 0x00, 0x00, //
+            0xc0, 0xa8, 0x01, 0x02, 0xc0, 0xa8, 0x01, 0x01, //
+            0x04, 0x02, 0x08, 0x0a, 0x00, 0x9a, 0x3f, 0x9c, //
+            0x00, 0x00, 0x00, 0x00, 0x01, 0x03, 0x03, 0x07,
+        ],
+    ];
+
+    /// IPv6 SYN behind a destination options header, split after the first 16 bytes
+    const IPV6_FRAGMENTS: &[&[u8]] = &[
+        &[
+            0x60, 0x00, 0x00, 0x00, 0x00, 0x20, 0x3c, 0x40, //
+            0x20, 0x01, 0x0d, 0xb8, 0x00, 0x00, 0x00, 0x00, //
+            0x00, 0x00, 0x00, 0x00, 0x00, 0x00, 0x00, 0x01, //
+            0x20, 0x01, 0x0d, 0xb8, 0x00, 0x00, 0x00, 0x00, //
+            0x00, 0x00, 0x00, 0x00, 0x00, 0x00, 0x00, 0x02, //
+            0x2c, 0x00, 0x01, 0x04, 0x00, 0x00, 0x00, 0x00, //
+            0x06, 0x00, 0x00, 0x01, 0x12, 0x34, 0x56, 0x78, //
+            0x30, 0x39, 0x00, 0x50, 0x00, 0x00, 0x00, 0x01, //
+            0x00, 0x00, 0x00, 0x00, 0x60, 0x02, 0xff, 0xff, //
+        ],
+        &[
+            0x60, 0x00, 0x00, 0x00, 0x00, 0x18, 0x3c, 0x40, //
+            0x20, 0x01, 0x0d, 0xb8, 0x00, 0x00, 0x00, 0x00, //
+            0x00, 0x00, 0x00, 0x00, 0x00, 0x00, 0x00, 0x01, //
+            0x20, 0x01, 0x0d, 0xb8, 0x00, 0x00, 0x00, 0x00, //
+            0x00, 0x00, 0x00, 0x00, 0x00, 0x00, 0x00, 0x02, //
+            0x2c, 0x00, 0x01, 0x04, 0x00, 0x00, 0x00, 0x00, //
+            0x06, 0x00, 0x00, 0x10, 0x12, 0x34, 0x56, 0x78, //
+            0x00, 0x00, 0x00, 0x00, 0x02, 0x04, 0x05, 0xa0, //
+        ],
+    ];
+
+    /// IPv6 fragment of the `IPV6_FRAGMENTS` datagram
+    fn ipv6_fragment(offset: u16, more: bool, data: &[u8]) -> Vec<u8> {
+        let frag_off = offset | if more { IP6_FRAG_MORE } else { 0 };
+        let mut packet = [
+            &IPV6_FRAGMENTS[1][..48],
+            &[0x06, 0x00],
+            &frag_off.to_be_bytes(),
+            &[0x12, 0x34, 0x56, 0x78],
+            data,
+        ]
+        .concat();
+        let len = (packet.len() - 40) as u16;
+
+        packet[4..6].copy_from_slice(&len.to_be_bytes());
+        packet
+    }
+
+    /// Outer IPv4 fragment from 10.0.0.1 to 10.0.0.2, carrying IP in IP
+    fn outer_fragment(offset: u16, more: bool, payload: &[u8]) -> Vec<u8> {
+        let len = (20 + payload.len()) as u16;
+        let frag_off = (offset / 8) | if more { 0x2000 } else { 0 };
+
+        [
+            &[0x45, 0x00][..],
+            &len.to_be_bytes(),
+            &[0xab, 0xcd],
+            &frag_off.to_be_bytes(),
+            &[0x40, 0x04, 0x00, 0x00, 10, 0, 0, 1, 10, 0, 0, 2],
+            payload,
+        ]
+        .concat()
+    }
+
+    fn ts(secs: u64) -> SystemTime {
+        SystemTime::UNIX_EPOCH + Duration::from_secs(secs)
+    }
+
+    #[test]
+    fn test_reassemble_ipv4() {
+        let mut reassembler = Reassembler::new();
+
+        assert!(Signature::extract_from(Datalink::Ipv4, IPV4_FRAGMENTS[0]).is_err());
+
+        // out of order, with a retransmitted fragment
+        for fragment in &[IPV4_FRAGMENTS[1], IPV4_FRAGMENTS[1]] {
+            assert!(reassembler
+                .extract(Datalink::Ipv4, fragment, ts(0))
+                .unwrap()
+                .is_none());
+        }
+
+        let (sig, obs) = reassembler
+            .extract(Datalink::Ipv4, IPV4_FRAGMENTS[0], ts(1))
+            .unwrap()
+            .unwrap();
+
+        assert_eq!(sig.version, crate::tcp::IpVersion::V4);
+        assert_eq!(sig.mss, Some(1460));
+        assert_eq!(sig.olayout.len(), 5);
+        assert_eq!(obs.encap, Encapsulation::default());
+        assert_eq!(obs.src_port, 54321);
+        assert_eq!(obs.tsval, Some(0x009a_3f9c));
+        assert!(reassembler.datagrams.is_empty());
+        assert_eq!(reassembler.buffered, 0);
+    }
+
+    #[test]
+    fn test_reassemble_padded_ipv4() {
+        let mut reassembler = Reassembler::new();
+        // padded to the minimum Ethernet frame size
+        let frames = IPV4_FRAGMENTS
+            .iter()
+            .map(|fragment| {
+                let mut frame = [&[0; 12][..], &[0x08, 0x00], fragment].concat();
+
+                frame.resize(60, 0);
+                frame
+            })
+            .collect::<Vec<_>>();
+
+        assert!(reassembler
+            .extract(Datalink::Ethernet, &frames[1], ts(0))
+            .unwrap()
+            .is_none());
+
+        let (sig, obs) = reassembler
+            .extract(Datalink::Ethernet, &frames[0], ts(0))
+            .unwrap()
+            .unwrap();
+
+        assert_eq!(sig.mss, Some(1460));
+        assert_eq!(sig.pclass, PayloadSize::Zero);
+        assert_eq!(obs.tsval, Some(0x009a_3f9c));
+        assert!(reassembler.datagrams.is_empty());
+
+        // the total length is past the captured packet
+        assert!(reassembler
+            .extract(Datalink::Ipv4, &IPV4_FRAGMENTS[0][..40], ts(0))
+            .is_err());
+    }
+
+    #[test]
+    fn test_non_tcp_fragments() {
+        let mut reassembler = Reassembler::new().with_max_datagrams(1);
+
+        assert!(reassembler
+            .extract(Datalink::Ipv4, IPV4_FRAGMENTS[1], ts(0))
+            .unwrap()
+            .is_none());
+
+        // ICMP fragments are not buffered, and leave room for the pending TCP datagram
+        for id in 0..4u8 {
+            let mut icmp = IPV4_FRAGMENTS[0].to_vec();
+
+            icmp[5] = id;
+            icmp[9] = IpNextHeaderProtocols::Icmp.0;
+
+            let err = reassembler
+                .extract(Datalink::Ipv4, &icmp, ts(0))
+                .unwrap_err();
+
+            assert!(err.downcast_ref::<Unsupported>().is_some(), "{}", err);
+        }
+
+        let mut icmpv6 = IPV6_FRAGMENTS[0].to_vec();
+
+        icmpv6[48] = IpNextHeaderProtocols::Icmpv6.0;
+
+        assert!(reassembler
+            .extract(Datalink::Ipv6, &icmpv6, ts(0))
+            .unwrap_err()
+            .downcast_ref::<Unsupported>()
+            .is_some());
+        assert_eq!(reassembler.datagrams.len(), 1);
+
+        let (sig, _) = reassembler
+            .extract(Datalink::Ipv4, IPV4_FRAGMENTS[0], ts(0))
+            .unwrap()
+            .unwrap();
+
+        assert_eq!(sig.mss, Some(1460));
+        assert!(reassembler.datagrams.is_empty());
+    }
+
+    #[test]
+    fn test_reassemble_ipv6() {
+        let mut reassembler = Reassembler::new();
+
+        assert!(reassembler
+            .extract(Datalink::Ipv6, IPV6_FRAGMENTS[0], ts(0))
+            .unwrap()
+            .is_none());
+
+        let (sig, _) = reassembler
+            .extract(Datalink::Ipv6, IPV6_FRAGMENTS[1], ts(0))
+            .unwrap()
+            .unwrap();
+
+        assert_eq!(sig.version, crate::tcp::IpVersion::V6);
+        assert_eq!(sig.olen, 8);
+        assert_eq!(sig.mss, Some(1440));
+        assert!(reassembler.datagrams.is_empty());
+    }
+
+    #[test]
+    fn test_reassemble_nested() {
+        let mut reassembler = Reassembler::new();
+        let (head, tail) = IPV4_FRAGMENTS[0].split_at(24);
+
+        // the inner datagram is fragmented, and its first fragment is fragmented again
+        for packet in &[
+            outer_fragment(0, false, IPV4_FRAGMENTS[1]),
+            outer_fragment(24, false, tail),
+        ] {
+            assert!(reassembler
+                .extract(Datalink::Ipv4, packet, ts(0))
+                .unwrap()
+                .is_none());
+        }
+
+        let (sig, obs) = reassembler
+            .extract(Datalink::Ipv4, &outer_fragment(0, true, head), ts(0))
+            .unwrap()
+            .unwrap();
+
+        assert_eq!(sig.mss, Some(1460));
+        assert_eq!(obs.tsval, Some(0x009a_3f9c));
+        assert_eq!(obs.encap.tunnels.len(), 1);
+        assert!(reassembler.datagrams.is_empty());
+        assert_eq!(reassembler.buffered, 0);
+    }
+
+    #[test]
+    fn test_expire() {
+        let mut reassembler = Reassembler::new().with_timeout(Duration::from_secs(10));
+
+        assert!(reassembler
+            .extract(Datalink::Ipv4, IPV4_FRAGMENTS[0], ts(0))
+            .unwrap()
+            .is_none());
+        assert!(reassembler
+            .extract(Datalink::Ipv4, IPV4_FRAGMENTS[1], ts(10))
+            .unwrap()
+            .is_none());
+        assert_eq!(reassembler.datagrams.len(), 1);
+        assert_eq!(reassembler.buffered, 16);
+
+        reassembler.expire(ts(20));
+
+        assert!(reassembler.datagrams.is_empty());
+        assert!(reassembler.by_age.is_empty());
+        assert_eq!(reassembler.buffered, 0);
+    }
+
+    #[test]
+    fn test_limits() {
+        // overlapping fragments drop the datagram
+        let mut reassembler = Reassembler::new();
+        let mut overlapped = IPV4_FRAGMENTS[1].to_vec();
+
+        overlapped[7] = 0x02;
+
+        assert!(reassembler
+            .extract(Datalink::Ipv4, IPV4_FRAGMENTS[0], ts(0))
+            .unwrap()
+            .is_none());
+        assert!(reassembler
+            .extract(Datalink::Ipv4, &overlapped, ts(0))
+            .is_err());
+        assert!(reassembler.datagrams.is_empty());
+        assert!(reassembler.by_age.is_empty());
+
+        // fragments past the maximum datagram size
+        let mut oversized = IPV4_FRAGMENTS[1].to_vec();
+
+        oversized[6] = 0x1f;
+        oversized[7] = 0xff;
+
+        assert!(reassembler
+            .extract(Datalink::Ipv4, &oversized, ts(0))
+            .is_err());
+
+        // reassembled IPv6 payload past the maximum datagram size, with the extension headers
+        let mut reassembler = Reassembler::new();
+
+        for (offset, more, data) in &[
+            (0, true, &IPV6_FRAGMENTS[0][56..]),
+            (16, true, &[0x00; 65504][..]),
+        ] {
+            assert!(reassembler
+                .extract(Datalink::Ipv6, &ipv6_fragment(*offset, *more, data), ts(0))
+                .unwrap()
+                .is_none());
+        }
+
+        let err = reassembler
+            .extract(
+                Datalink::Ipv6,
+                &ipv6_fragment(65520, false, &[0x00; 8]),
+                ts(0),
+            )
+            .unwrap_err();
+
+        assert!(err.to_string().contains("too large"), "{}", err);
+        assert!(reassembler.datagrams.is_empty());
+
+        // too many datagrams
+        let mut reassembler = Reassembler::new().with_max_datagrams(1);
+        let mut other = IPV4_FRAGMENTS[0].to_vec();
+
+        other[5] = 0x47;
+
+        assert!(reassembler
+            .extract(Datalink::Ipv4, IPV4_FRAGMENTS[0], ts(0))
+            .unwrap()
+            .is_none());
+        assert!(reassembler.extract(Datalink::Ipv4, &other, ts(0)).is_err());
+
+        // too many bytes
+        let mut reassembler = Reassembler::new().with_max_bytes(16);
+
+        assert!(reassembler
+            .extract(Datalink::Ipv4, IPV4_FRAGMENTS[0], ts(0))
+            .is_err());
+
+        // too many fragments
+        let mut reassembler = Reassembler::new().with_max_fragments(1);
+
+        assert!(reassembler
+            .extract(Datalink::Ipv4, IPV4_FRAGMENTS[0], ts(0))
+            .unwrap()
+            .is_none());
+        assert!(reassembler
+            .extract(Datalink::Ipv4, IPV4_FRAGMENTS[1], ts(0))
+            .is_err());
+        assert!(reassembler.datagrams.is_empty());
+    }
+}
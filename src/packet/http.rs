@@ -0,0 +1,439 @@
+use std::time::{Duration, SystemTime, UNIX_EPOCH};
+
+use failure::{bail, err_msg, Error};
+
+use crate::http::{Header, Signature, Version};
+
+/// Headers that usually appear in requests, reported as absent when missing.
+const REQUEST_COMMON: &[&str] = &[
+    "Host",
+    "User-Agent",
+    "Connection",
+    "Accept",
+    "Accept-Encoding",
+    "Accept-Language",
+    "Accept-Charset",
+    "Keep-Alive",
+];
+
+/// Headers that may or may not appear in requests from the same client.
+const REQUEST_OPTIONAL: &[&str] = &[
+    "Cookie",
+    "Referer",
+    "Origin",
+    "Range",
+    "If-Modified-Since",
+    "If-None-Match",
+    "Via",
+    "X-Forwarded-For",
+    "Authorization",
+    "Proxy-Authorization",
+    "Cache-Control",
+];
+
+/// Headers in requests whose value is not part of the signature.
+const REQUEST_SKIP_VALUE: &[&str] = &["Host", "User-Agent"];
+
+/// Headers that usually appear in responses, reported as absent when missing.
+const RESPONSE_COMMON: &[&str] = &[
+    "Content-Type",
+    "Connection",
+    "Keep-Alive",
+    "Accept-Ranges",
+    "Date",
+];
+
+/// Headers that may or may not appear in responses from the same server.
+const RESPONSE_OPTIONAL: &[&str] = &[
+    "Set-Cookie",
+    "Last-Modified",
+    "ETag",
+    "Content-Length",
+    "Content-Disposition",
+    "Cache-Control",
+    "Expires",
+    "Pragma",
+    "Location",
+    "Refresh",
+    "Content-Range",
+    "Vary",
+];
+
+/// Headers in responses whose value is not part of the signature.
+const RESPONSE_SKIP_VALUE: &[&str] = &["Date", "Content-Type", "Server"];
+
+/// Fields of the HTTP headers that are not part of the signature.
+#[derive(Clone, Debug, Default, PartialEq)]
+pub struct HttpObservation {
+    /// 'Date' header.
+    pub date: Option<SystemTime>,
+    /// 'Accept-Language' header, most preferred language first.
+    pub languages: Vec<String>,
+    /// 'Via' header.
+    pub via: Option<String>,
+    /// 'X-Forwarded-For' header.
+    pub forwarded_for: Option<String>,
+    /// 'Forwarded' header.
+    pub forwarded: Option<String>,
+}
+
+impl HttpObservation {
+    /// Offset of the sender's clock to the capture time, in seconds, from the 'Date' header.
+    ///
+    /// Positive if the sender's clock is ahead.
+    pub fn clock_skew(&self, ts: SystemTime) -> Option<i64> {
+        self.date.map(|date| match date.duration_since(ts) {
+            Ok(ahead) => ahead.as_secs() as i64,
+            Err(err) => -(err.duration().as_secs() as i64),
+        })
+    }
+
+    /// Most preferred language of the client.
+    pub fn language(&self) -> Option<&str> {
+        self.languages.first().map(String::as_str)
+    }
+}
+
+impl Signature {
+    /// Extract the signature from the headers of an HTTP/1.x request or response.
+    ///
+    /// The 'User-Agent' or 'Server' value is kept as `expsw`.
+    pub fn extract(payload: &[u8]) -> Result<Self, Error> {
+        Signature::observe(payload).map(|(sig, _)| sig)
+    }
+
+    /// Extract the signature, and the header fields that are not part of it.
+    pub fn observe(payload: &[u8]) -> Result<(Self, HttpObservation), Error> {
+        // only lines terminated by a line feed are complete
+        let mut lines = payload
+            .split_inclusive(|&b| b == b'\n')
+            .filter_map(|line| line.strip_suffix(b"\n"))
+            .map(|line| {
+                String::from_utf8_lossy(line.strip_suffix(b"\r").unwrap_or(line)).into_owned()
+            });
+
+        let start_line = lines
+            .next()
+            .ok_or_else(|| err_msg("HTTP start line missing"))?;
+        let (is_request, version) = parse_start_line(&start_line)?;
+
+        let (common, optional, skip_value, software) = if is_request {
+            (
+                REQUEST_COMMON,
+                REQUEST_OPTIONAL,
+                REQUEST_SKIP_VALUE,
+                "User-Agent",
+            )
+        } else {
+            (
+                RESPONSE_COMMON,
+                RESPONSE_OPTIONAL,
+                RESPONSE_SKIP_VALUE,
+                "Server",
+            )
+        };
+
+        let mut horder = vec![];
+        let mut expsw = String::new();
+        let mut obs = HttpObservation::default();
+        let mut complete = false;
+
+        for line in lines {
+            if line.is_empty() {
+                complete = true;
+                break;
+            }
+
+            let pos = line
+                .find(':')
+                .ok_or_else(|| err_msg("malformed HTTP header"))?;
+            let name = line[..pos].trim();
+            let value = line[pos + 1..].trim();
+
+            if name.is_empty() || name.contains(char::is_whitespace) {
+                bail!("malformed HTTP header name: {}", name);
+            }
+
+            if name.eq_ignore_ascii_case(software) {
+                expsw = value.to_owned();
+            } else if name.eq_ignore_ascii_case("Date") {
+                obs.date = parse_date(value);
+            } else if name.eq_ignore_ascii_case("Accept-Language") {
+                obs.languages = parse_languages(value);
+            } else if name.eq_ignore_ascii_case("Via") {
+                obs.via = Some(value.to_owned());
+            } else if name.eq_ignore_ascii_case("X-Forwarded-For") {
+                obs.forwarded_for = Some(value.to_owned());
+            } else if name.eq_ignore_ascii_case("Forwarded") {
+                obs.forwarded = Some(value.to_owned());
+            }
+
+            let mut header = Header::new(name);
+
+            if contains(optional, name) {
+                header = header.optional();
+            } else if !contains(skip_value, name) {
+                header = header.with_value(value);
+            }
+
+            horder.push(header);
+        }
+
+        if !complete {
+            bail!("incomplete HTTP headers");
+        }
+
+        let habsent = common
+            .iter()
+            .filter(|&&name| !horder.iter().any(|h| h.name.eq_ignore_ascii_case(name)))
+            .map(Header::new)
+            .collect();
+
+        let sig = Signature {
+            version,
+            horder,
+            habsent,
+            expsw,
+        };
+
+        Ok((sig, obs))
+    }
+}
+
+/// Parse the request or status line, returns whether it's a request and the HTTP version.
+fn parse_start_line(line: &str) -> Result<(bool, Version), Error> {
+    let parts = line.split(' ').collect::<Vec<_>>();
+
+    let (is_request, version) = match parts.as_slice() {
+        [version, _status, ..] if version.starts_with("HTTP/") => (false, *version),
+        [_method, _uri, version] => (true, *version),
+        _ => bail!("malformed HTTP start line: {}", line),
+    };
+
+    let version = match version {
+        "HTTP/1.0" => Version::V10,
+        "HTTP/1.1" => Version::V11,
+        _ => bail!("unsupport HTTP version: {}", version),
+    };
+
+    Ok((is_request, version))
+}
+
+/// Parse an 'Accept-Language' value, ordered by decreasing quality.
+///
+/// Languages with a zero or malformed quality are dropped.
+fn parse_languages(value: &str) -> Vec<String> {
+    let mut languages = value
+        .split(',')
+        .filter_map(|item| {
+            let mut params = item.split(';').map(str::trim);
+            let tag = params.next().filter(|tag| !tag.is_empty())?;
+            let mut q = 1.0;
+
+            for param in params {
+                if let Some(value) = param
+                    .strip_prefix("q=")
+                    .or_else(|| param.strip_prefix("Q="))
+                {
+                    q = value
+                        .parse::<f32>()
+                        .ok()
+                        .filter(|q| (0.0..=1.0).contains(q))?;
+                }
+            }
+
+            if q > 0.0 {
+                Some((tag.to_owned(), q))
+            } else {
+                None
+            }
+        })
+        .collect::<Vec<_>>();
+
+    // stable, the order of the header breaks the ties
+    languages.sort_by(|a, b| b.1.partial_cmp(&a.1).unwrap());
+    languages.into_iter().map(|(tag, _)| tag).collect()
+}
+
+const MONTHS: &[&str] = &[
+    "Jan", "Feb", "Mar", "Apr", "May", "Jun", "Jul", "Aug", "Sep", "Oct", "Nov", "Dec",
+];
+
+/// Parse an HTTP date, in the IMF-fixdate, RFC 850 or asctime format, from 1970 to 9999.
+fn parse_date(value: &str) -> Option<SystemTime> {
+    let tokens = value
+        .split(|c: char| c.is_whitespace() || c == ',' || c == '-')
+        .filter(|s| !s.is_empty())
+        .collect::<Vec<_>>();
+
+    let (day, month, year, time) = match tokens.as_slice() {
+        [_, day, month, year, time, "GMT"] => (day, month, year, time),
+        [_, month, day, time, year] => (day, month, year, time),
+        _ => return None,
+    };
+
+    let day = day.parse::<u64>().ok().filter(|d| (1..=31).contains(d))?;
+    let month = MONTHS.iter().position(|m| m == month)? as u64 + 1;
+    let year = match year.parse::<u64>().ok()? {
+        // RFC 850 two-digit year
+        year @ 0..=69 => year + 2000,
+        year @ 70..=99 => year + 1900,
+        year @ 1970..=9999 => year,
+        _ => return None,
+    };
+    let time = time
+        .split(':')
+        .map(|s| s.parse::<u64>().ok())
+        .collect::<Option<Vec<_>>>()?;
+    let secs = match time.as_slice() {
+        [h, m, s] if *h < 24 && *m < 60 && *s < 61 => h * 3600 + m * 60 + s,
+        _ => return None,
+    };
+
+    let secs = days_from_civil(year, month, day)
+        .checked_mul(86400)?
+        .checked_add(secs)?;
+
+    UNIX_EPOCH.checked_add(Duration::from_secs(secs))
+}
+
+/// Days since the Unix epoch of a date in the proleptic Gregorian calendar.
+fn days_from_civil(year: u64, month: u64, day: u64) -> u64 {
+    let year = if month <= 2 { year - 1 } else { year };
+    let era = year / 400;
+    let yoe = year - era * 400;
+    let doy = (153 * ((month + 9) % 12) + 2) / 5 + day - 1;
+    let doe = yoe * 365 + yoe / 4 - yoe / 100 + doy;
+
+    era * 146_097 + doe - 719_468
+}
+
+fn contains(names: &[&str], name: &str) -> bool {
+    names.iter().any(|n| n.eq_ignore_ascii_case(name))
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[cfg(feature = "display")]
+    #[test]
+    fn test_extract() {
+        for (payload, sig) in &[
+            (
+                &b"GET /index.html HTTP/1.1\r\n\
+                Host: www.example.com\r\n\
+                User-Agent: Mozilla/5.0 (X11; Linux x86_64; rv:2.0) Firefox/2.0\r\n\
+                Accept: text/html,*/*;q=0.8\r\n\
+                Accept-Encoding: gzip,deflate\r\n\
+                Cookie: session=1234\r\n\
+                Connection: keep-alive\r\n\
+                \r\n"[..],
+                "1:Host,User-Agent,Accept=[text/html,*/*;q=0.8],Accept-Encoding=[gzip,deflate],?Cookie,Connection=[keep-alive]:Accept-Language,Accept-Charset,Keep-Alive:Mozilla/5.0 (X11; Linux x86_64; rv:2.0) Firefox/2.0",
+            ),
+            (
+                &b"HTTP/1.0 200 OK\r\n\
+                Server: Apache/2.2.22\r\n\
+                Date: Mon, 01 Jan 2018 00:00:00 GMT\r\n\
+                Content-Type: text/html\r\n\
+                Content-Length: 42\r\n\
+                Connection: close\r\n\
+                \r\n\
+                <html>"[..],
+                "0:Server,Date,Content-Type,?Content-Length,Connection=[close]:Keep-Alive,Accept-Ranges:Apache/2.2.22",
+            ),
+        ] {
+            assert_eq!(Signature::extract(payload).unwrap().to_string(), *sig);
+        }
+    }
+
+    #[test]
+    fn test_observe() {
+        let (_, obs) = Signature::observe(
+            b"HTTP/1.1 200 OK\r\n\
+            Date: Mon, 01 Jan 2018 00:00:00 GMT\r\n\
+            Server: Apache/2.4.29\r\n\
+            \r\n",
+        )
+        .unwrap();
+
+        let date = UNIX_EPOCH + Duration::from_secs(1_514_764_800);
+
+        assert_eq!(obs.date, Some(date));
+        assert_eq!(obs.clock_skew(date), Some(0));
+        assert_eq!(obs.clock_skew(date + Duration::from_secs(90)), Some(-90));
+        assert_eq!(obs.clock_skew(date - Duration::from_secs(30)), Some(30));
+
+        let (_, obs) = Signature::observe(b"GET / HTTP/1.1\r\nHost: example.com\r\n\r\n").unwrap();
+
+        assert_eq!(obs.date, None);
+        assert_eq!(obs.clock_skew(date), None);
+    }
+
+    #[test]
+    fn test_parse_languages() {
+        for (value, languages) in &[
+            ("en-US,en;q=0.5", vec!["en-US", "en"]),
+            (
+                "de;q=0.7, fr-CH, fr;q=0.9, en;q=0.8, *;q=0.5",
+                vec!["fr-CH", "fr", "en", "de", "*"],
+            ),
+            ("pl;q=0.5,ru;q=0.5,uk", vec!["uk", "pl", "ru"]),
+            ("en;q=0, da", vec!["da"]),
+            ("en;q=2, da;q=x, ja", vec!["ja"]),
+            ("", vec![]),
+        ] {
+            assert_eq!(&parse_languages(value), languages, "{}", value);
+        }
+
+        let (_, obs) = Signature::observe(
+            b"GET / HTTP/1.1\r\n\
+            Host: example.com\r\n\
+            Accept-Language: fr;q=0.8, en-GB\r\n\
+            \r\n",
+        )
+        .unwrap();
+
+        assert_eq!(obs.languages, vec!["en-GB", "fr"]);
+        assert_eq!(obs.language(), Some("en-GB"));
+    }
+
+    #[test]
+    fn test_parse_date() {
+        for (value, secs) in &[
+            ("Sun, 06 Nov 1994 08:49:37 GMT", Some(784_111_777)),
+            ("Sunday, 06-Nov-94 08:49:37 GMT", Some(784_111_777)),
+            ("Sun Nov  6 08:49:37 1994", Some(784_111_777)),
+            ("Thu, 29 Feb 2024 23:59:59 GMT", Some(1_709_251_199)),
+            ("Thu, 01 Jan 1970 00:00:00 GMT", Some(0)),
+            ("Sun, 06 Nov 1994 08:49:37 PST", None),
+            ("Sun, 06 Foo 1994 08:49:37 GMT", None),
+            ("Sun, 06 Nov 1994 25:49:37 GMT", None),
+            ("Wed, 31 Dec 1969 23:59:59 GMT", None),
+            ("Fri, 31 Dec 9999 23:59:59 GMT", Some(253_402_300_799)),
+            ("Sat, 01 Jan 10000 00:00:00 GMT", None),
+            ("Sun, 06 Nov 300000000000000 08:49:37 GMT", None),
+            ("yesterday", None),
+        ] {
+            assert_eq!(
+                parse_date(value),
+                secs.map(|secs| UNIX_EPOCH + Duration::from_secs(secs)),
+                "{}",
+                value
+            );
+        }
+    }
+
+    #[test]
+    fn test_extract_malformed() {
+        for payload in &[
+            &b""[..],
+            &b"GET / HTTP/1.1\r\nHost: www.example.com\r\n"[..],
+            &b"GET / HTTP/2.0\r\nHost: www.example.com\r\n\r\n"[..],
+            &b"GET / HTTP/1.1\r\nHost www.example.com\r\n\r\n"[..],
+            &b"\x16\x03\x01\x02\x00\x01\x00\x01\xfc\x03\x03"[..],
+        ] {
+            assert!(Signature::extract(payload).is_err());
+        }
+    }
+}
@@ -0,0 +1,443 @@
+use std::convert::TryInto;
+
+use failure::{bail, err_msg, Error};
+
+use pnet::packet::{
+    ethernet::{EtherType, EtherTypes, EthernetPacket},
+    Packet,
+};
+
+use crate::tcp::Signature;
+
+use super::{visit_ethernet, Encapsulation, Observation, MAX_ENCAPSULATION_DEPTH};
+
+/// Link-layer header type of the captured packets.
+#[derive(Clone, Copy, Debug, PartialEq)]
+pub enum Datalink {
+    /// BSD loopback encapsulation, with the address family in host byte order.
+    Null,
+    /// IEEE 802.3 Ethernet
+    Ethernet,
+    /// Raw IP, the packet begins with an IPv4 or IPv6 header.
+    Raw,
+    /// PPP, with or without the HDLC-like framing.
+    Ppp,
+    /// OpenBSD loopback encapsulation, with the address family in network byte order.
+    Loop,
+    /// Linux "cooked" capture encapsulation.
+    LinuxSll,
+    /// Linux "cooked" capture encapsulation v2.
+    LinuxSll2,
+    /// Raw IPv4, the packet begins with an IPv4 header.
+    Ipv4,
+    /// Raw IPv6, the packet begins with an IPv6 header.
+    Ipv6,
+}
+
+impl Datalink {
+    /// Convert from the `LINKTYPE_*` value of a pcap or pcapng file.
+    pub fn from_linktype(linktype: u32) -> Option<Self> {
+        match linktype {
+            0 => Some(Datalink::Null),
+            1 => Some(Datalink::Ethernet),
+            9 | 50 => Some(Datalink::Ppp),
+            // DLT_RAW is 12 on most platforms, and 14 on OpenBSD
+            12 | 14 | 101 => Some(Datalink::Raw),
+            108 => Some(Datalink::Loop),
+            113 => Some(Datalink::LinuxSll),
+            228 => Some(Datalink::Ipv4),
+            229 => Some(Datalink::Ipv6),
+            276 => Some(Datalink::LinuxSll2),
+            _ => None,
+        }
+    }
+}
+
+/// IPv4 address family
+const AF_INET: u32 = 2;
+/// IPv6 address family on Linux, NetBSD/OpenBSD, FreeBSD and macOS
+const AF_INET6: &[u32] = &[10, 24, 28, 30];
+
+/// PPP protocol field for IPv4
+const PPP_IP: u16 = 0x0021;
+/// PPP protocol field for IPv6
+const PPP_IPV6: u16 = 0x0057;
+
+impl Signature {
+    /// Extract the signature from a packet with the given link-layer header type.
+    pub fn extract_from(datalink: Datalink, packet: &[u8]) -> Result<Self, Error> {
+        Signature::observe(datalink, packet).map(|(sig, _)| sig)
+    }
+
+    /// Extract the signature, and the encapsulation stripped before reaching the IP packet.
+    pub fn extract_encapsulated(
+        datalink: Datalink,
+        packet: &[u8],
+    ) -> Result<(Self, Encapsulation), Error> {
+        Signature::observe(datalink, packet).map(|(sig, obs)| (sig, obs.encap))
+    }
+
+    /// Extract the signature, and the observed packet fields that are not part of it.
+    ///
+    /// Only SYN and SYN+ACK segments are accepted.
+    pub fn observe(datalink: Datalink, packet: &[u8]) -> Result<(Self, Observation), Error> {
+        let (sig, obs) = Signature::observe_any(datalink, packet)?;
+
+        obs.ensure_handshake()?;
+
+        Ok((sig, obs))
+    }
+
+    /// Extract the signature from any TCP segment, see `Observation::segment`.
+    pub fn observe_any(datalink: Datalink, packet: &[u8]) -> Result<(Self, Observation), Error> {
+        let mut encap = Encapsulation::default();
+
+        let (sig, mut obs) = match datalink {
+            Datalink::Ethernet => visit_ethernet_frame(packet, &mut encap),
+            Datalink::Null | Datalink::Loop => visit_loopback(datalink, packet, &mut encap),
+            Datalink::Raw => visit_ip(packet, &mut encap),
+            Datalink::Ipv4 => visit_ethernet(EtherTypes::Ipv4, packet, &mut encap),
+            Datalink::Ipv6 => visit_ethernet(EtherTypes::Ipv6, packet, &mut encap),
+            Datalink::Ppp => visit_ppp(packet, &mut encap),
+            Datalink::LinuxSll => visit_linux_sll(packet, &mut encap),
+            Datalink::LinuxSll2 => visit_linux_sll2(packet, &mut encap),
+        }?;
+
+        obs.encap = encap;
+
+        Ok((sig, obs))
+    }
+}
+
+pub(super) fn visit_ethernet_frame(
+    packet: &[u8],
+    encap: &mut Encapsulation,
+) -> Result<(Signature, Observation), Error> {
+    EthernetPacket::new(packet)
+        .ok_or_else(|| err_msg("ethernet packet too short"))
+        .and_then(|packet| visit_ethernet(packet.get_ethertype(), packet.payload(), encap))
+}
+
+/// Raw IP packet, the version is taken from the first nibble.
+fn visit_ip(packet: &[u8], encap: &mut Encapsulation) -> Result<(Signature, Observation), Error> {
+    match packet.first().map(|b| b >> 4) {
+        Some(4) => visit_ethernet(EtherTypes::Ipv4, packet, encap),
+        Some(6) => visit_ethernet(EtherTypes::Ipv6, packet, encap),
+        Some(version) => bail!("unsupport IP version: {}", version),
+        None => bail!("IP packet too short"),
+    }
+}
+
+fn visit_loopback(
+    datalink: Datalink,
+    packet: &[u8],
+    encap: &mut Encapsulation,
+) -> Result<(Signature, Observation), Error> {
+    if packet.len() < 4 {
+        bail!("loopback packet too short");
+    }
+
+    let header = packet[..4].try_into()?;
+    let family = if datalink == Datalink::Loop {
+        u32::from_be_bytes(header)
+    } else if u32::from_ne_bytes(header) <= 0xffff {
+        u32::from_ne_bytes(header)
+    } else {
+        // captured on a host with a different byte order
+        u32::from_ne_bytes(header).swap_bytes()
+    };
+
+    match family {
+        AF_INET => visit_ethernet(EtherTypes::Ipv4, &packet[4..], encap),
+        _ if AF_INET6.contains(&family) => visit_ethernet(EtherTypes::Ipv6, &packet[4..], encap),
+        _ => unsupported!("unsupport loopback address family: {}", family),
+    }
+}
+
+fn visit_ppp(packet: &[u8], encap: &mut Encapsulation) -> Result<(Signature, Observation), Error> {
+    // skip the HDLC-like address and control fields
+    let packet = if packet.starts_with(&[0xff, 0x03]) {
+        &packet[2..]
+    } else {
+        packet
+    };
+
+    visit_ppp_payload(packet, encap)
+}
+
+pub(super) fn visit_ppp_payload(
+    packet: &[u8],
+    encap: &mut Encapsulation,
+) -> Result<(Signature, Observation), Error> {
+    // the protocol field may be compressed into a single byte
+    let (protocol, payload) = match packet {
+        [b, payload @ ..] if b & 0x01 == 0x01 => (u16::from(*b), payload),
+        [hi, lo, payload @ ..] => (u16::from_be_bytes([*hi, *lo]), payload),
+        _ => bail!("PPP packet too short"),
+    };
+
+    match protocol {
+        PPP_IP => visit_ethernet(EtherTypes::Ipv4, payload, encap),
+        PPP_IPV6 => visit_ethernet(EtherTypes::Ipv6, payload, encap),
+        _ => unsupported!("unsupport PPP protocol: {:#06x}", protocol),
+    }
+}
+
+/// PPPoE version and type of the session stage
+const PPPOE_VER_TYPE: u8 = 0x11;
+/// PPPoE code of the session data
+const PPPOE_CODE_SESSION: u8 = 0x00;
+
+pub(super) fn visit_pppoe(
+    packet: &[u8],
+    encap: &mut Encapsulation,
+) -> Result<(Signature, Observation), Error> {
+    if packet.len() < 6 {
+        bail!("PPPoE packet too short");
+    }
+    if packet[0] != PPPOE_VER_TYPE || packet[1] != PPPOE_CODE_SESSION {
+        bail!(
+            "unsupport PPPoE packet: {:#04x} {:#04x}",
+            packet[0],
+            packet[1]
+        );
+    }
+
+    let session = u16::from_be_bytes([packet[2], packet[3]]);
+    let len = usize::from(u16::from_be_bytes([packet[4], packet[5]]));
+    let payload = &packet[6..];
+
+    encap.pppoe_session = Some(session);
+
+    visit_ppp_payload(&payload[..len.min(payload.len())], encap)
+}
+
+/// Bottom of the MPLS label stack
+const MPLS_BOTTOM_OF_STACK: u32 = 0x100;
+
+pub(super) fn visit_mpls(
+    mut packet: &[u8],
+    encap: &mut Encapsulation,
+) -> Result<(Signature, Observation), Error> {
+    loop {
+        if packet.len() < 4 {
+            bail!("MPLS packet too short");
+        }
+
+        let entry = u32::from_be_bytes(packet[..4].try_into()?);
+
+        encap.mpls_labels.push(entry >> 12);
+        packet = &packet[4..];
+
+        if encap.depth() > MAX_ENCAPSULATION_DEPTH {
+            bail!("too many nested encapsulations: {}", encap.depth());
+        }
+
+        if entry & MPLS_BOTTOM_OF_STACK != 0 {
+            break;
+        }
+    }
+
+    // MPLS doesn't identify the payload, guess it from the first nibble
+    match packet.first().map(|b| b >> 4) {
+        Some(4) | Some(6) => visit_ip(packet, encap),
+        // pseudowire control word, followed by an ethernet frame
+        Some(0) if packet.len() >= 4 => visit_ethernet_frame(&packet[4..], encap),
+        None | Some(0) => bail!("MPLS payload too short"),
+        _ => unsupported!("unsupport MPLS payload"),
+    }
+}
+
+fn visit_linux_sll(
+    packet: &[u8],
+    encap: &mut Encapsulation,
+) -> Result<(Signature, Observation), Error> {
+    if packet.len() < 16 {
+        bail!("Linux cooked packet too short");
+    }
+
+    let protocol = EtherType::new(u16::from_be_bytes([packet[14], packet[15]]));
+
+    visit_ethernet(protocol, &packet[16..], encap)
+}
+
+fn visit_linux_sll2(
+    packet: &[u8],
+    encap: &mut Encapsulation,
+) -> Result<(Signature, Observation), Error> {
+    if packet.len() < 20 {
+        bail!("Linux cooked v2 packet too short");
+    }
+
+    let protocol = EtherType::new(u16::from_be_bytes([packet[0], packet[1]]));
+
+    visit_ethernet(protocol, &packet[20..], encap)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    const IPV4_SYN: &[u8] = &[
+        0x45, 0x00, 0x00, 0x2c, 0x1c, 0x46, 0x40, 0x00, 0x40, 0x06, 0x00, 0x00, //
+        0xc0, 0xa8, 0x01, 0x02, 0xc0, 0xa8, 0x01, 0x01, //
+        0xd4, 0x31, 0x00, 0x50, 0x6e, 0x3b, 0x5b, 0x2c, 0x00, 0x00, 0x00, 0x00, //
+        0x60, 0x02, 0x72, 0x10, 0x00, 0x00, 0x00, 0x00, //
+        0x02, 0x04, 0x05, 0xb4,
+    ];
+
+    #[test]
+    fn test_extract_from() {
+        for (datalink, header) in &[
+            (
+                Datalink::Ethernet,
+                &[
+                    0x00, 0x11, 0x22, 0x33, 0x44, 0x55, 0x66, 0x77, 0x88, 0x99, 0xaa, 0xbb, 0x08,
+                    0x00,
+                ][..],
+            ),
+            (Datalink::Raw, &[][..]),
+            (Datalink::Ipv4, &[][..]),
+            (Datalink::Null, &AF_INET.to_ne_bytes()[..]),
+            (Datalink::Null, &AF_INET.swap_bytes().to_ne_bytes()[..]),
+            (Datalink::Loop, &AF_INET.to_be_bytes()[..]),
+            (Datalink::Ppp, &[0xff, 0x03, 0x00, 0x21][..]),
+            (Datalink::Ppp, &[0x00, 0x21][..]),
+            (Datalink::Ppp, &[0x21][..]),
+            (
+                Datalink::LinuxSll,
+                &[
+                    0x00, 0x00, 0x00, 0x01, 0x00, 0x06, 0x00, 0x11, 0x22, 0x33, 0x44, 0x55, 0x00,
+                    0x00, 0x08, 0x00,
+                ][..],
+            ),
+            (
+                Datalink::LinuxSll2,
+                &[
+                    0x08, 0x00, 0x00, 0x00, 0x00, 0x00, 0x00, 0x02, 0x00, 0x01, 0x00, 0x06, 0x00,
+                    0x11, 0x22, 0x33, 0x44, 0x55, 0x00, 0x00,
+                ][..],
+            ),
+        ] {
+            let packet = [header, IPV4_SYN].concat();
+            let sig = Signature::extract_from(*datalink, &packet).unwrap();
+
+            assert_eq!(sig.mss, Some(1460), "{:?}", datalink);
+        }
+    }
+
+    #[test]
+    fn test_extract_encapsulated() {
+        const ETHERNET: &[u8] = &[
+            0x00, 0x11, 0x22, 0x33, 0x44, 0x55, 0x66, 0x77, 0x88, 0x99, 0xaa, 0xbb,
+        ];
+
+        for (header, encap) in &[
+            // 802.1ad
+            (
+                &[0x88, 0xa8, 0x00, 0x64, 0x81, 0x00, 0x00, 0xc8, 0x08, 0x00][..],
+                Encapsulation {
+                    vlan_ids: vec![100, 200],
+                    ..Default::default()
+                },
+            ),
+            // MPLS
+            (
+                &[0x88, 0x47, 0x00, 0x3e, 0x80, 0x40, 0x00, 0x7d, 0x01, 0x40][..],
+                Encapsulation {
+                    mpls_labels: vec![1000, 2000],
+                    ..Default::default()
+                },
+            ),
+            // MPLS pseudowire with control word
+            (
+                &[
+                    0x88, 0x47, 0x00, 0x01, 0x01, 0x40, 0x00, 0x00, 0x00, 0x00, 0x00, 0x11, 0x22,
+                    0x33, 0x44, 0x55, 0x66, 0x77, 0x88, 0x99, 0xaa, 0xbb, 0x08, 0x00,
+                ][..],
+                Encapsulation {
+                    mpls_labels: vec![16],
+                    ..Default::default()
+                },
+            ),
+            // PPPoE session
+            (
+                &[0x88, 0x64, 0x11, 0x00, 0x12, 0x34, 0x00, 0x2e, 0x00, 0x21][..],
+                Encapsulation {
+                    pppoe_session: Some(0x1234),
+                    ..Default::default()
+                },
+            ),
+            // VLAN over PPPoE
+            (
+                &[
+                    0x81, 0x00, 0x00, 0x0a, 0x88, 0x64, 0x11, 0x00, 0x00, 0x01, 0x00, 0x2e, 0x00,
+                    0x21,
+                ][..],
+                Encapsulation {
+                    vlan_ids: vec![10],
+                    pppoe_session: Some(1),
+                    ..Default::default()
+                },
+            ),
+        ] {
+            let packet = [ETHERNET, header, IPV4_SYN].concat();
+            let (sig, e) = Signature::extract_encapsulated(Datalink::Ethernet, &packet).unwrap();
+
+            assert_eq!(sig.mss, Some(1460));
+            assert_eq!(&e, encap);
+        }
+    }
+
+    #[test]
+    fn test_extract_from_malformed() {
+        for (datalink, packet) in &[
+            (Datalink::Raw, &[0x25, 0x00][..]),
+            (Datalink::Null, &[0x02, 0x00][..]),
+            (Datalink::Loop, &[0x00, 0x00, 0x00, 0x07][..]),
+            (Datalink::Ppp, &[0xff, 0x03, 0xc0, 0x21][..]),
+            (Datalink::LinuxSll, &[0x00; 15][..]),
+            (Datalink::LinuxSll2, &[0x00; 19][..]),
+            (Datalink::Raw, &[0x08, 0x00][..]),
+        ] {
+            assert!(Signature::extract_from(*datalink, packet).is_err());
+        }
+    }
+
+    #[test]
+    fn test_nested_too_deep() {
+        let ethernet = [
+            0x00, 0x11, 0x22, 0x33, 0x44, 0x55, 0x66, 0x77, 0x88, 0x99, 0xaa, 0xbb, 0x81, 0x00,
+        ];
+        let vlan = [0x00, 0x64, 0x81, 0x00];
+        let mpls = [0x00, 0x01, 0x00, 0x40];
+
+        let vlans = [&ethernet[..], &vlan.repeat(10000), IPV4_SYN].concat();
+        let labels = [
+            &ethernet[..12],
+            &[0x88, 0x47],
+            &mpls.repeat(10000),
+            IPV4_SYN,
+        ]
+        .concat();
+
+        for packet in &[vlans, labels] {
+            let err = Signature::extract_from(Datalink::Ethernet, packet).unwrap_err();
+
+            assert!(err.to_string().starts_with("too many nested"), "{}", err);
+        }
+
+        // a few nested layers are fine
+        let vlans = [
+            &ethernet[..],
+            &vlan.repeat(3),
+            &[0x00, 0x64, 0x08, 0x00],
+            IPV4_SYN,
+        ]
+        .concat();
+
+        let (_, encap) = Signature::extract_encapsulated(Datalink::Ethernet, &vlans).unwrap();
+
+        assert_eq!(encap.vlan_ids, vec![100; 4]);
+    }
+}
@@ -0,0 +1,497 @@
+use std::net::IpAddr;
+
+use failure::{bail, err_msg, Error};
+
+use pnet::packet::{
+    ethernet::{EtherType, EtherTypes},
+    ip::{IpNextHeaderProtocol, IpNextHeaderProtocols},
+    udp::UdpPacket,
+    Packet,
+};
+
+use crate::tcp::Signature;
+
+use super::{link, visit_ethernet, Encapsulation, Observation};
+
+/// Tunnel stripped before reaching the inner IP packet.
+#[derive(Clone, Debug, PartialEq)]
+pub struct Tunnel {
+    pub kind: TunnelKind,
+    /// source address of the outer IP header.
+    pub src: IpAddr,
+    /// destination address of the outer IP header.
+    pub dst: IpAddr,
+    /// GRE key, ERSPAN session ID, VXLAN or Geneve network identifier, if any.
+    pub id: Option<u32>,
+}
+
+#[derive(Clone, Copy, Debug, PartialEq)]
+pub enum TunnelKind {
+    /// Generic Routing Encapsulation, including PPTP
+    Gre,
+    /// Encapsulated Remote SPAN over GRE
+    Erspan,
+    /// Virtual eXtensible Local Area Network
+    Vxlan,
+    /// Generic Network Virtualization Encapsulation
+    Geneve,
+    /// IPv4 encapsulated in IP
+    IpInIp,
+    /// IPv6 encapsulated in IP, e.g. 6in4
+    Ipv6InIp,
+}
+
+/// Checksum present
+const GRE_CHECKSUM: u16 = 0x8000;
+/// Key present
+const GRE_KEY: u16 = 0x2000;
+/// Sequence number present
+const GRE_SEQ: u16 = 0x1000;
+/// Acknowledgment number present, only for the enhanced GRE of PPTP
+const GRE_ACK: u16 = 0x0080;
+/// GRE version
+const GRE_VERSION: u16 = 0x0007;
+
+/// PPP, used by PPTP
+const GRE_PROTO_PPP: u16 = 0x880b;
+/// Transparent ethernet bridging
+const GRE_PROTO_TEB: u16 = 0x6558;
+/// ERSPAN type I and II
+const GRE_PROTO_ERSPAN2: u16 = 0x88be;
+/// ERSPAN type III
+const GRE_PROTO_ERSPAN3: u16 = 0x22eb;
+
+/// Optional platform specific subheader present in ERSPAN type III
+const ERSPAN3_SUBHEADER: u8 = 0x01;
+
+/// VXLAN flag of the valid network identifier
+const VXLAN_VNI: u8 = 0x08;
+
+const VXLAN_PORT: u16 = 4789;
+const GENEVE_PORT: u16 = 6081;
+
+/// The IP protocol could carry a tunnel decoded by `visit_tunnel`.
+pub(super) fn is_tunnel(protocol: IpNextHeaderProtocol) -> bool {
+    matches!(
+        protocol,
+        IpNextHeaderProtocols::Ipv4
+            | IpNextHeaderProtocols::Ipv6
+            | IpNextHeaderProtocols::Gre
+            | IpNextHeaderProtocols::Udp
+    )
+}
+
+pub(super) fn visit_tunnel(
+    protocol: IpNextHeaderProtocol,
+    src: IpAddr,
+    dst: IpAddr,
+    payload: &[u8],
+    encap: &mut Encapsulation,
+) -> Result<(Signature, Observation), Error> {
+    match protocol {
+        IpNextHeaderProtocols::Ipv4 => {
+            encap.tunnels.push(Tunnel {
+                kind: TunnelKind::IpInIp,
+                src,
+                dst,
+                id: None,
+            });
+
+            visit_ethernet(EtherTypes::Ipv4, payload, encap)
+        }
+        IpNextHeaderProtocols::Ipv6 => {
+            encap.tunnels.push(Tunnel {
+                kind: TunnelKind::Ipv6InIp,
+                src,
+                dst,
+                id: None,
+            });
+
+            visit_ethernet(EtherTypes::Ipv6, payload, encap)
+        }
+        IpNextHeaderProtocols::Gre => visit_gre(src, dst, payload, encap),
+        IpNextHeaderProtocols::Udp => UdpPacket::new(payload)
+            .ok_or_else(|| err_msg("UDP packet too short"))
+            .and_then(|packet| visit_udp(src, dst, packet, encap)),
+        _ => unsupported!("unsupported IP packet with non-TCP payload: {}", protocol),
+    }
+}
+
+fn visit_gre(
+    src: IpAddr,
+    dst: IpAddr,
+    packet: &[u8],
+    encap: &mut Encapsulation,
+) -> Result<(Signature, Observation), Error> {
+    if packet.len() < 4 {
+        bail!("GRE packet too short");
+    }
+
+    let flags = u16::from_be_bytes([packet[0], packet[1]]);
+    let protocol = u16::from_be_bytes([packet[2], packet[3]]);
+    let version = flags & GRE_VERSION;
+
+    if version > 1 {
+        unsupported!("unsupported GRE version: {}", version);
+    }
+
+    let mut offset = 4;
+    let mut key = None;
+
+    if flags & GRE_CHECKSUM != 0 {
+        offset += 4;
+    }
+    if flags & GRE_KEY != 0 {
+        key = Some(read_u32(packet, offset)?);
+        offset += 4;
+    }
+    if flags & GRE_SEQ != 0 {
+        offset += 4;
+    }
+    if version == 1 && flags & GRE_ACK != 0 {
+        offset += 4;
+    }
+    if packet.len() < offset {
+        bail!("GRE packet too short");
+    }
+
+    let payload = &packet[offset..];
+
+    match protocol {
+        GRE_PROTO_ERSPAN2 | GRE_PROTO_ERSPAN3 => {
+            // ERSPAN type I has no header, and is sent without sequence number
+            let len = match protocol {
+                GRE_PROTO_ERSPAN2 if flags & GRE_SEQ == 0 => 0,
+                GRE_PROTO_ERSPAN2 => 8,
+                _ if payload.len() >= 12 && payload[11] & ERSPAN3_SUBHEADER != 0 => 20,
+                _ => 12,
+            };
+
+            if payload.len() < len {
+                bail!("ERSPAN packet too short");
+            }
+
+            encap.tunnels.push(Tunnel {
+                kind: TunnelKind::Erspan,
+                src,
+                dst,
+                id: if len > 0 {
+                    Some(u32::from(
+                        u16::from_be_bytes([payload[2], payload[3]]) & 0x03ff,
+                    ))
+                } else {
+                    None
+                },
+            });
+
+            link::visit_ethernet_frame(&payload[len..], encap)
+        }
+        _ => {
+            encap.tunnels.push(Tunnel {
+                kind: TunnelKind::Gre,
+                src,
+                dst,
+                id: key,
+            });
+
+            match protocol {
+                GRE_PROTO_PPP => link::visit_ppp_payload(payload, encap),
+                GRE_PROTO_TEB => link::visit_ethernet_frame(payload, encap),
+                ethertype => visit_ethernet(EtherType::new(ethertype), payload, encap),
+            }
+        }
+    }
+}
+
+fn visit_udp(
+    src: IpAddr,
+    dst: IpAddr,
+    packet: UdpPacket,
+    encap: &mut Encapsulation,
+) -> Result<(Signature, Observation), Error> {
+    let payload = packet.payload();
+
+    match packet.get_destination() {
+        VXLAN_PORT => {
+            if payload.len() < 8 {
+                bail!("VXLAN packet too short");
+            }
+
+            encap.tunnels.push(Tunnel {
+                kind: TunnelKind::Vxlan,
+                src,
+                dst,
+                id: if payload[0] & VXLAN_VNI != 0 {
+                    Some(read_u32(payload, 4)? >> 8)
+                } else {
+                    None
+                },
+            });
+
+            link::visit_ethernet_frame(&payload[8..], encap)
+        }
+        GENEVE_PORT => {
+            if payload.len() < 8 {
+                bail!("Geneve packet too short");
+            }
+
+            let len = 8 + usize::from(payload[0] & 0x3f) * 4;
+            let protocol = u16::from_be_bytes([payload[2], payload[3]]);
+
+            if payload.len() < len {
+                bail!("Geneve packet too short");
+            }
+
+            encap.tunnels.push(Tunnel {
+                kind: TunnelKind::Geneve,
+                src,
+                dst,
+                id: Some(read_u32(payload, 4)? >> 8),
+            });
+
+            match protocol {
+                GRE_PROTO_TEB => link::visit_ethernet_frame(&payload[len..], encap),
+                ethertype => visit_ethernet(EtherType::new(ethertype), &payload[len..], encap),
+            }
+        }
+        port => unsupported!("unsupported UDP packet to port {}", port),
+    }
+}
+
+fn read_u32(buf: &[u8], offset: usize) -> Result<u32, Error> {
+    buf.get(offset..offset + 4)
+        .map(|b| u32::from_be_bytes([b[0], b[1], b[2], b[3]]))
+        .ok_or_else(|| err_msg("tunnel header too short"))
+}
+
+#[cfg(test)]
+mod tests {
+    use std::net::{Ipv4Addr, Ipv6Addr};
+
+    use super::*;
+    use crate::packet::Datalink;
+    use crate::tcp::IpVersion;
+
+    const ETHERNET: &[u8] = &[
+        0x00, 0x11, 0x22, 0x33, 0x44, 0x55, 0x66, 0x77, 0x88, 0x99, 0xaa, 0xbb, 0x08, 0x00,
+    ];
+
+    const IPV4_SYN: &[u8] = &[
+        0x45, 0x00, 0x00, 0x2c, 0x1c, 0x46, 0x40, 0x00, 0x40, 0x06, 0x00, 0x00, //
+        0xc0, 0xa8, 0x01, 0x02, 0xc0, 0xa8, 0x01, 0x01, //
+        0xd4, 0x31, 0x00, 0x50, 0x6e, 0x3b, 0x5b, 0x2c, 0x00, 0x00, 0x00, 0x00, //
+        0x60, 0x02, 0x72, 0x10, 0x00, 0x00, 0x00, 0x00, //
+        0x02, 0x04, 0x05, 0xb4,
+    ];
+
+    const IPV6_SYN: &[u8] = &[
+        0x60, 0x00, 0x00, 0x00, 0x00, 0x18, 0x06, 0x40, //
+        0x20, 0x01, 0x0d, 0xb8, 0x00, 0x00, 0x00, 0x00, //
+        0x00, 0x00, 0x00, 0x00, 0x00, 0x00, 0x00, 0x01, //
+        0x20, 0x01, 0x0d, 0xb8, 0x00, 0x00, 0x00, 0x00, //
+        0x00, 0x00, 0x00, 0x00, 0x00, 0x00, 0x00, 0x02, //
+        0xd4, 0x31, 0x00, 0x50, 0x6e, 0x3b, 0x5b, 0x2c, 0x00, 0x00, 0x00, 0x00, //
+        0x60, 0x02, 0x70, 0x80, 0x00, 0x00, 0x00, 0x00, //
+        0x02, 0x04, 0x05, 0xa0,
+    ];
+
+    /// Outer IPv4 header from 10.0.0.1 to 10.0.0.2
+    fn ipv4(protocol: u8, payload: &[u8]) -> Vec<u8> {
+        let len = (20 + payload.len()) as u16;
+
+        [
+            &[0x45, 0x00][..],
+            &len.to_be_bytes(),
+            &[0x00, 0x00, 0x40, 0x00, 0x40, protocol, 0x00, 0x00],
+            &[10, 0, 0, 1, 10, 0, 0, 2],
+            payload,
+        ]
+        .concat()
+    }
+
+    /// UDP header to the given port
+    fn udp(port: u16, payload: &[u8]) -> Vec<u8> {
+        let len = (8 + payload.len()) as u16;
+
+        [
+            &[0x30, 0x39][..],
+            &port.to_be_bytes(),
+            &len.to_be_bytes(),
+            &[0x00, 0x00],
+            payload,
+        ]
+        .concat()
+    }
+
+    #[test]
+    fn test_tunnels() {
+        let inner_frame = [ETHERNET, IPV4_SYN].concat();
+
+        for (packet, version, kind, id) in &[
+            (ipv4(4, IPV4_SYN), IpVersion::V4, TunnelKind::IpInIp, None),
+            (
+                ipv4(41, IPV6_SYN),
+                IpVersion::V6,
+                TunnelKind::Ipv6InIp,
+                None,
+            ),
+            (
+                ipv4(
+                    47,
+                    &[
+                        &[0x20, 0x00, 0x08, 0x00, 0x00, 0x00, 0x00, 0x64][..],
+                        IPV4_SYN,
+                    ]
+                    .concat(),
+                ),
+                IpVersion::V4,
+                TunnelKind::Gre,
+                Some(100),
+            ),
+            (
+                ipv4(
+                    47,
+                    &[
+                        &[
+                            0xb0, 0x00, 0x86, 0xdd, 0x00, 0x00, 0x00, 0x00, 0x00, 0x00, 0x00, 0x07,
+                            0x00, 0x00, 0x00, 0x01,
+                        ][..],
+                        IPV6_SYN,
+                    ]
+                    .concat(),
+                ),
+                IpVersion::V6,
+                TunnelKind::Gre,
+                Some(7),
+            ),
+            (
+                ipv4(
+                    47,
+                    &[
+                        &[
+                            0x10, 0x00, 0x88, 0xbe, 0x00, 0x00, 0x00, 0x01, //
+                            0x10, 0x00, 0x00, 0x05, 0x00, 0x00, 0x00, 0x00,
+                        ][..],
+                        &inner_frame,
+                    ]
+                    .concat(),
+                ),
+                IpVersion::V4,
+                TunnelKind::Erspan,
+                Some(5),
+            ),
+            (
+                ipv4(
+                    17,
+                    &udp(
+                        VXLAN_PORT,
+                        &[
+                            &[0x08, 0x00, 0x00, 0x00, 0x00, 0x00, 0x64, 0x00][..],
+                            &inner_frame,
+                        ]
+                        .concat(),
+                    ),
+                ),
+                IpVersion::V4,
+                TunnelKind::Vxlan,
+                Some(100),
+            ),
+            (
+                ipv4(
+                    17,
+                    &udp(
+                        GENEVE_PORT,
+                        &[
+                            &[
+                                0x02, 0x00, 0x65, 0x58, 0x00, 0x00, 0xc8, 0x00, //
+                                0x01, 0x02, 0x03, 0x01, 0x00, 0x00, 0x00, 0x00,
+                            ][..],
+                            &inner_frame,
+                        ]
+                        .concat(),
+                    ),
+                ),
+                IpVersion::V4,
+                TunnelKind::Geneve,
+                Some(200),
+            ),
+        ] {
+            let (sig, encap) = Signature::extract_encapsulated(Datalink::Raw, packet).unwrap();
+
+            assert_eq!(sig.version, *version);
+            assert_eq!(
+                encap.tunnels,
+                vec![Tunnel {
+                    kind: *kind,
+                    src: Ipv4Addr::new(10, 0, 0, 1).into(),
+                    dst: Ipv4Addr::new(10, 0, 0, 2).into(),
+                    id: *id,
+                }]
+            );
+        }
+    }
+
+    #[test]
+    fn test_nested_tunnels() {
+        // IPv6 in IPv6, inside IPv4 in IPv4
+        let inner = [
+            &[
+                0x60, 0x00, 0x00, 0x00, 0x00, 0x40, 0x29, 0x40, //
+                0x20, 0x01, 0x0d, 0xb8, 0x00, 0x00, 0x00, 0x00, //
+                0x00, 0x00, 0x00, 0x00, 0x00, 0x00, 0x00, 0x0a, //
+                0x20, 0x01, 0x0d, 0xb8, 0x00, 0x00, 0x00, 0x00, //
+                0x00, 0x00, 0x00, 0x00, 0x00, 0x00, 0x00, 0x0b,
+            ][..],
+            IPV6_SYN,
+        ]
+        .concat();
+        let packet = ipv4(41, &inner);
+
+        let (sig, encap) = Signature::extract_encapsulated(Datalink::Raw, &packet).unwrap();
+
+        assert_eq!(sig.version, IpVersion::V6);
+        assert_eq!(
+            encap.tunnels,
+            vec![
+                Tunnel {
+                    kind: TunnelKind::Ipv6InIp,
+                    src: Ipv4Addr::new(10, 0, 0, 1).into(),
+                    dst: Ipv4Addr::new(10, 0, 0, 2).into(),
+                    id: None,
+                },
+                Tunnel {
+                    kind: TunnelKind::Ipv6InIp,
+                    src: "2001:db8::a".parse::<Ipv6Addr>().unwrap().into(),
+                    dst: "2001:db8::b".parse::<Ipv6Addr>().unwrap().into(),
+                    id: None,
+                }
+            ]
+        );
+    }
+
+    #[test]
+    fn test_nested_too_deep() {
+        let mut packet = IPV4_SYN.to_vec();
+
+        for _ in 0..1000 {
+            packet = ipv4(4, &packet);
+        }
+
+        let err = Signature::extract_from(Datalink::Raw, &packet).unwrap_err();
+
+        assert!(err.to_string().starts_with("too many nested"), "{}", err);
+    }
+
+    #[test]
+    fn test_unsupported() {
+        for packet in &[
+            ipv4(17, &udp(53, &[0x00; 12])),
+            ipv4(47, &[0x00, 0x02, 0x08, 0x00]),
+            ipv4(47, &[0x20, 0x00, 0x08, 0x00, 0x00]),
+            ipv4(17, &udp(VXLAN_PORT, &[0x08, 0x00, 0x00])),
+            ipv4(1, &[0x08, 0x00, 0x00, 0x00]),
+        ] {
+            assert!(Signature::extract_from(Datalink::Raw, packet).is_err());
+        }
+    }
+}
@@ -0,0 +1,262 @@
+use std::net::{IpAddr, SocketAddr};
+
+use crate::{db::HttpMatch, http, packet::HttpObservation, Label};
+
+/// Software whose header order gives away a proxy, when matched instead of the client.
+const KNOWN_PROXIES: &[&str] = &[
+    "Squid",
+    "Privoxy",
+    "Polipo",
+    "Varnish",
+    "HAProxy",
+    "Blue Coat",
+    "Apache Traffic Server",
+];
+
+/// Evidence that an HTTP request went through a proxy.
+#[derive(Clone, Copy, Debug, PartialEq)]
+pub enum ProxyReason {
+    /// the 'Via' header is present.
+    Via,
+    /// the 'X-Forwarded-For' header is present.
+    ForwardedFor,
+    /// the 'Forwarded' header is present.
+    Forwarded,
+    /// the header order matches other software than the claimed 'User-Agent',
+    /// e.g. a known proxy, or a client whose headers were rewritten.
+    HeaderOrder,
+}
+
+/// HTTP request sent by a proxy on behalf of a client.
+#[derive(Clone, Debug, PartialEq)]
+pub struct Proxy {
+    /// address of the original client, as forwarded by the proxy.
+    pub client: Option<IpAddr>,
+    /// 'Via' header, the protocols and names of the proxies.
+    pub via: Option<String>,
+    /// known proxy matched from the header order.
+    pub label: Option<Label>,
+    /// the reasons, in the order they were checked.
+    pub reasons: Vec<ProxyReason>,
+}
+
+impl Proxy {
+    /// Check whether an HTTP request went through a proxy, given its best match, if any.
+    ///
+    /// The header order is evidence when the match is dishonest, or when it's a known proxy
+    /// the 'User-Agent' doesn't mention, which needs proxy labels in the database.
+    /// The client address is taken from the first hop of 'Forwarded', or else 'X-Forwarded-For'.
+    pub fn detect(
+        sig: &http::Signature,
+        obs: &HttpObservation,
+        m: Option<&HttpMatch<'_>>,
+    ) -> Option<Proxy> {
+        let mut reasons = vec![];
+
+        if obs.via.is_some() {
+            reasons.push(ProxyReason::Via);
+        }
+        if obs.forwarded_for.is_some() {
+            reasons.push(ProxyReason::ForwardedFor);
+        }
+        if obs.forwarded.is_some() {
+            reasons.push(ProxyReason::Forwarded);
+        }
+
+        let label = m
+            .filter(|m| is_known_proxy(&m.label.name) && !claims(&sig.expsw, &m.label.name))
+            .map(|m| m.label.clone());
+
+        if label.is_some() || matches!(m, Some(m) if m.dishonest) {
+            reasons.push(ProxyReason::HeaderOrder);
+        }
+
+        if reasons.is_empty() {
+            return None;
+        }
+
+        let client = obs
+            .forwarded
+            .as_deref()
+            .and_then(parse_forwarded)
+            .or_else(|| obs.forwarded_for.as_deref().and_then(parse_forwarded_for));
+
+        Some(Proxy {
+            client,
+            via: obs.via.clone(),
+            label,
+            reasons,
+        })
+    }
+}
+
+fn is_known_proxy(name: &str) -> bool {
+    KNOWN_PROXIES.iter().any(|p| p.eq_ignore_ascii_case(name))
+}
+
+/// The 'User-Agent' mentions the software.
+fn claims(user_agent: &str, name: &str) -> bool {
+    user_agent
+        .to_ascii_lowercase()
+        .contains(&name.to_ascii_lowercase())
+}
+
+/// First hop of an 'X-Forwarded-For' value, e.g. `203.0.113.7, 10.0.0.1`.
+fn parse_forwarded_for(value: &str) -> Option<IpAddr> {
+    value.split(',').next().and_then(parse_node)
+}
+
+/// First hop of a 'Forwarded' value, e.g. `for="[2001:db8::17]:4711";proto=http, for=10.0.0.1`.
+fn parse_forwarded(value: &str) -> Option<IpAddr> {
+    value.split(',').next()?.split(';').find_map(|pair| {
+        let pos = pair.find('=')?;
+
+        if pair[..pos].trim().eq_ignore_ascii_case("for") {
+            parse_node(&pair[pos + 1..])
+        } else {
+            None
+        }
+    })
+}
+
+/// Address of a node, with an optional port and quotes, but not obfuscated or "unknown".
+fn parse_node(node: &str) -> Option<IpAddr> {
+    let node = node.trim().trim_matches('"');
+
+    node.parse::<IpAddr>()
+        .ok()
+        .or_else(|| node.parse::<SocketAddr>().ok().map(|addr| addr.ip()))
+        .or_else(|| {
+            node.strip_prefix('[')
+                .and_then(|node| node.strip_suffix(']'))
+                .and_then(|node| node.parse().ok())
+        })
+}
+
+#[cfg(all(test, feature = "parse"))]
+mod tests {
+    use super::*;
+    use crate::db::{Database, Direction};
+
+    const DATABASE: &str = r#"
+[http:request]
+
+label = s:!:Firefox:2.x
+sig   = *:Host,User-Agent,Accept=[,*/*;q=],?Accept-Language,Accept-Encoding=[gzip,deflate],Accept-Charset=[utf-8;q=0.7,*;q=0.7],Keep-Alive=[300],Connection=[keep-alive]::Firefox/
+
+label = s:!:Squid:
+sig   = 1:Host,User-Agent,Accept,Via,X-Forwarded-For,Cache-Control,Connection=[keep-alive]:Accept-Encoding:
+"#;
+
+    fn detect(db: &Database, request: &[u8]) -> Option<Proxy> {
+        let (sig, obs) = http::Signature::observe(request).unwrap();
+
+        Proxy::detect(&sig, &obs, db.match_http(&sig, Direction::Request).as_ref())
+    }
+
+    #[test]
+    fn test_detect_proxy() {
+        let db: Database = DATABASE.parse().unwrap();
+
+        assert_eq!(
+            detect(
+                &db,
+                b"GET / HTTP/1.1\r\n\
+                Host: example.com\r\n\
+                User-Agent: Mozilla/5.0 (X11; Linux x86_64; rv:2.0) Firefox/2.0\r\n\
+                Accept: text/html,*/*;q=0.8\r\n\
+                Accept-Encoding: gzip,deflate\r\n\
+                Accept-Charset: utf-8;q=0.7,*;q=0.7\r\n\
+                Keep-Alive: 300\r\n\
+                Connection: keep-alive\r\n\
+                \r\n",
+            ),
+            None
+        );
+
+        let proxy = detect(
+            &db,
+            b"GET / HTTP/1.1\r\n\
+            Host: example.com\r\n\
+            User-Agent: Mozilla/5.0 (X11; Linux x86_64; rv:2.0) Firefox/2.0\r\n\
+            Accept: text/html,*/*;q=0.8\r\n\
+            Via: 1.1 proxy.example.net (squid/3.5.27)\r\n\
+            X-Forwarded-For: 203.0.113.7, 10.0.0.1\r\n\
+            Cache-Control: max-age=259200\r\n\
+            Connection: keep-alive\r\n\
+            \r\n",
+        )
+        .unwrap();
+
+        assert_eq!(proxy.client, Some("203.0.113.7".parse().unwrap()));
+        assert_eq!(
+            proxy.via.as_deref(),
+            Some("1.1 proxy.example.net (squid/3.5.27)")
+        );
+        assert_eq!(proxy.label.unwrap().name, "Squid");
+        assert_eq!(
+            proxy.reasons,
+            vec![
+                ProxyReason::Via,
+                ProxyReason::ForwardedFor,
+                ProxyReason::HeaderOrder
+            ]
+        );
+
+        let proxy = detect(
+            &db,
+            b"GET / HTTP/1.1\r\n\
+            Host: example.com\r\n\
+            User-Agent: curl/7.68.0\r\n\
+            Forwarded: for=\"[2001:db8:cafe::17]:4711\";proto=http, for=10.0.0.1\r\n\
+            X-Forwarded-For: 198.51.100.1\r\n\
+            \r\n",
+        )
+        .unwrap();
+
+        assert_eq!(proxy.client, Some("2001:db8:cafe::17".parse().unwrap()));
+        assert_eq!(proxy.label, None);
+        assert_eq!(
+            proxy.reasons,
+            vec![ProxyReason::ForwardedFor, ProxyReason::Forwarded]
+        );
+
+        // the headers of Firefox, but not its 'User-Agent'
+        let proxy = detect(
+            &db,
+            b"GET / HTTP/1.1\r\n\
+            Host: example.com\r\n\
+            User-Agent: curl/7.68.0\r\n\
+            Accept: text/html,*/*;q=0.8\r\n\
+            Accept-Encoding: gzip,deflate\r\n\
+            Accept-Charset: utf-8;q=0.7,*;q=0.7\r\n\
+            Keep-Alive: 300\r\n\
+            Connection: keep-alive\r\n\
+            \r\n",
+        )
+        .unwrap();
+
+        assert_eq!(proxy.client, None);
+        assert_eq!(proxy.label, None);
+        assert_eq!(proxy.reasons, vec![ProxyReason::HeaderOrder]);
+    }
+
+    #[test]
+    fn test_parse_node() {
+        for (node, addr) in &[
+            ("192.0.2.60", Some("192.0.2.60")),
+            (" 192.0.2.60:8080", Some("192.0.2.60")),
+            ("\"[2001:db8::1]\"", Some("2001:db8::1")),
+            ("2001:db8::1", Some("2001:db8::1")),
+            ("unknown", None),
+            ("_hidden", None),
+        ] {
+            assert_eq!(
+                parse_node(node),
+                addr.map(|addr| addr.parse().unwrap()),
+                "{}",
+                node
+            );
+        }
+    }
+}
@@ -30,6 +30,7 @@ pub enum IpVersion {
 pub enum TTL {
     Value(u8),
     Distance(u8, u8),
+    /// observed TTL, too far from any initial TTL to tell the distance.
     Guess(u8),
     Bad(u8),
 }
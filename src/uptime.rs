@@ -0,0 +1,194 @@
+use std::time::{Duration, SystemTime};
+
+/// Minimum interval between the samples, shorter ones are too imprecise.
+pub const MIN_TWAIT: Duration = Duration::from_millis(25);
+/// Maximum interval between the samples, the host could have rebooted in-between.
+pub const MAX_TWAIT: Duration = Duration::from_secs(10 * 60);
+
+/// Minimum timestamp clock frequency, in Hz.
+const MIN_TSCALE: f64 = 0.7;
+/// Maximum timestamp clock frequency, in Hz.
+const MAX_TSCALE: f64 = 1500.0;
+
+/// Resolution of the capture time, in seconds.
+const CLOCK_RES: f64 = 0.001;
+
+/// TCP timestamp value, and the time it was captured.
+#[derive(Clone, Copy, Debug, PartialEq)]
+pub struct Sample {
+    pub tsval: u32,
+    pub seen: SystemTime,
+}
+
+/// Uptime and timestamp clock frequency of a host.
+#[derive(Clone, Debug, PartialEq)]
+pub struct Uptime {
+    /// measured frequency of the timestamp clock, in Hz.
+    pub raw_freq: f64,
+    /// lower and upper bounds of the measured frequency.
+    pub freq_bounds: (f64, f64),
+    /// frequency rounded to a value commonly used by operating systems, in Hz.
+    pub freq: u32,
+    pub uptime: Duration,
+    /// lower and upper bounds of the uptime.
+    pub uptime_bounds: (Duration, Duration),
+    /// the timestamp clock wraps around after this period, the uptime is modulo it.
+    pub wraparound: Duration,
+}
+
+impl Uptime {
+    /// Estimate the uptime from two timestamps of the same host, the way p0f does.
+    ///
+    /// Returns `None` if the samples are too close or too far apart in time,
+    /// if the timestamp went backwards or moved less than two ticks,
+    /// or if the frequency is implausible.
+    pub fn estimate(first: Sample, last: Sample) -> Option<Self> {
+        let elapsed = last.seen.duration_since(first.seen).ok()?;
+
+        if elapsed < MIN_TWAIT || elapsed > MAX_TWAIT {
+            return None;
+        }
+
+        let ticks = last.tsval.wrapping_sub(first.tsval);
+
+        // a single tick leaves the lower frequency bound at zero
+        if !(2..=u32::MAX / 2).contains(&ticks) {
+            return None;
+        }
+
+        let secs = elapsed.as_secs_f64();
+        let raw_freq = f64::from(ticks) / secs;
+
+        if !(MIN_TSCALE..=MAX_TSCALE).contains(&raw_freq) {
+            return None;
+        }
+
+        // one tick and the capture time resolution of uncertainty on both samples
+        let freq_bounds = (
+            (f64::from(ticks) - 1.0) / (secs + 2.0 * CLOCK_RES),
+            (f64::from(ticks) + 1.0) / (secs - 2.0 * CLOCK_RES),
+        );
+
+        if !(freq_bounds.0 > 0.0 && freq_bounds.1.is_finite()) {
+            return None;
+        }
+
+        let freq = round_freq(raw_freq as u32);
+        let tsval = f64::from(last.tsval);
+
+        Some(Uptime {
+            raw_freq,
+            freq_bounds,
+            freq,
+            uptime: Duration::from_secs(u64::from(last.tsval / freq)),
+            uptime_bounds: (
+                duration(tsval / freq_bounds.1.max(f64::from(freq)))?,
+                duration(tsval / freq_bounds.0.min(f64::from(freq)))?,
+            ),
+            wraparound: Duration::from_secs(u64::from(u32::MAX / freq)),
+        })
+    }
+}
+
+/// Seconds as a duration, `None` if negative, not a number or too large.
+fn duration(secs: f64) -> Option<Duration> {
+    if secs >= 0.0 && secs < u64::MAX as f64 {
+        Some(Duration::from_secs_f64(secs))
+    } else {
+        None
+    }
+}
+
+/// Round the frequency to a value commonly used by operating systems.
+fn round_freq(freq: u32) -> u32 {
+    match freq {
+        0 => 1,
+        1..=10 => freq,
+        11..=50 => (freq + 3) / 5 * 5,
+        51..=100 => (freq + 7) / 10 * 10,
+        101..=500 => (freq + 33) / 50 * 50,
+        _ => (freq + 67) / 100 * 100,
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn sample(tsval: u32, millis: u64) -> Sample {
+        Sample {
+            tsval,
+            seen: SystemTime::UNIX_EPOCH + Duration::from_millis(millis),
+        }
+    }
+
+    #[test]
+    fn test_estimate() {
+        let uptime = Uptime::estimate(sample(1_000_000, 0), sample(1_010_000, 10_000)).unwrap();
+
+        assert_eq!(uptime.raw_freq, 1000.0);
+        assert_eq!(uptime.freq, 1000);
+        assert!(uptime.freq_bounds.0 < 1000.0 && 1000.0 < uptime.freq_bounds.1);
+        assert_eq!(uptime.uptime, Duration::from_secs(1010));
+        assert!(uptime.uptime_bounds.0 <= uptime.uptime);
+        assert!(uptime.uptime <= uptime.uptime_bounds.1);
+        assert_eq!(uptime.wraparound, Duration::from_secs(4_294_967));
+
+        // 100 Hz, measured a bit off
+        let uptime = Uptime::estimate(sample(8_640_000, 0), sample(8_640_099, 1000)).unwrap();
+
+        assert_eq!(uptime.freq, 100);
+        assert_eq!(uptime.uptime, Duration::from_secs(86400));
+
+        // timestamp wrapped around
+        let uptime = Uptime::estimate(sample(u32::MAX - 99, 0), sample(900, 1000)).unwrap();
+
+        assert_eq!(uptime.freq, 1000);
+        assert_eq!(uptime.uptime, Duration::from_secs(0));
+    }
+
+    #[test]
+    fn test_estimate_invalid() {
+        for (first, last) in &[
+            // too close in time
+            (sample(1000, 0), sample(1010, 10)),
+            // too far apart in time
+            (sample(1000, 0), sample(2000, 3_600_000)),
+            // went backwards
+            (sample(2000, 0), sample(1000, 1000)),
+            // not moving
+            (sample(1000, 0), sample(1000, 1000)),
+            // a single tick
+            (sample(1000, 0), sample(1001, 1000)),
+            // too fast
+            (sample(1000, 0), sample(1_000_000, 1000)),
+        ] {
+            assert_eq!(Uptime::estimate(*first, *last), None);
+        }
+    }
+
+    #[test]
+    fn test_duration() {
+        assert_eq!(duration(1.5), Some(Duration::from_millis(1500)));
+        assert_eq!(duration(0.0), Some(Duration::from_secs(0)));
+
+        for invalid in &[-1.0, f64::NAN, f64::INFINITY, u64::MAX as f64] {
+            assert_eq!(duration(*invalid), None);
+        }
+    }
+
+    #[test]
+    fn test_round_freq() {
+        for (freq, rounded) in &[
+            (0, 1),
+            (2, 2),
+            (11, 10),
+            (13, 15),
+            (98, 100),
+            (240, 250),
+            (1010, 1000),
+        ] {
+            assert_eq!(round_freq(*freq), *rounded);
+        }
+    }
+}